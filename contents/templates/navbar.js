@@ -4,6 +4,8 @@
     navbar_id = "home";
   } else if (window.location.pathname.startsWith("/posts")) {
     navbar_id = "posts";
+  } else if (window.location.pathname.startsWith("/search")) {
+    navbar_id = "search";
   } else if (window.location.pathname.startsWith("/contact")) {
     navbar_id = "contact";
   }
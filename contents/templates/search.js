@@ -0,0 +1,91 @@
+(() => {
+  const input = document.querySelector("#search-input");
+  const results = document.querySelector("#search-results");
+  let indexPromise = null;
+
+  const loadIndex = () => {
+    indexPromise ??= fetch("/search.json").then((response) => response.json());
+    return indexPromise;
+  };
+
+  // This must match `tokenize` in search.rs
+  const tokenize = (text) =>
+    text
+      .split(/[^\p{Alphabetic}\p{N}]+/u)
+      .filter((term) => [...term].length > 1)
+      .map((term) => term.toLowerCase());
+
+  const search = async (query) => {
+    const { posts, terms } = await loadIndex();
+    let scores = null;
+    for (const token of tokenize(query)) {
+      const tokenScores = new Map();
+      for (const [term, hits] of Object.entries(terms)) {
+        if (!term.startsWith(token)) {
+          continue;
+        }
+        for (const [post, weight] of hits) {
+          tokenScores.set(post, (tokenScores.get(post) ?? 0) + weight);
+        }
+      }
+      if (scores === null) {
+        scores = tokenScores;
+        continue;
+      }
+      for (const [post, score] of scores) {
+        if (tokenScores.has(post)) {
+          scores.set(post, score + tokenScores.get(post));
+        } else {
+          scores.delete(post);
+        }
+      }
+    }
+    return [...(scores ?? [])]
+      .sort(([, a], [, b]) => b - a)
+      .map(([post]) => posts[post]);
+  };
+
+  const renderResults = (query, matches) => {
+    results.replaceChildren();
+    if (query.trim() === "") {
+      return;
+    }
+    if (matches.length === 0) {
+      const item = document.createElement("li");
+      item.textContent = "No posts found";
+      results.append(item);
+      return;
+    }
+    for (const post of matches) {
+      const link = document.createElement("a");
+      link.href = `/posts/${post.slug}/`;
+      link.textContent = post.title;
+      const details = document.createElement("span");
+      details.textContent = ` ${post.date} ${post.tags.join(", ")}`;
+      const item = document.createElement("li");
+      item.append(link, details);
+      results.append(item);
+    }
+  };
+
+  const update = async () => {
+    const query = input.value;
+    const matches = await search(query);
+    if (input.value === query) {
+      renderResults(query, matches);
+    }
+    const url = new URL(window.location);
+    if (query === "") {
+      url.searchParams.delete("q");
+    } else {
+      url.searchParams.set("q", query);
+    }
+    window.history.replaceState(null, "", url);
+  };
+
+  input.addEventListener("input", update);
+  input.value = new URLSearchParams(window.location.search).get("q") ?? "";
+  if (input.value !== "") {
+    update();
+  }
+})();
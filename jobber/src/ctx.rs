@@ -1,6 +1,7 @@
 use std::{
     collections::HashSet,
     hash::{BuildHasher as _, Hash},
+    num::NonZeroUsize,
     path::Path,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
@@ -126,6 +127,59 @@ impl JobCtx<'_> {
         result
     }
 
+    /// Map `items` with `f` on a thread per core, returning the first error once all are mapped
+    ///
+    /// The execution time added is CPU time summed across threads. Jobs are not deduplicated while
+    /// running, so two items calling the same uncached job both run it.
+    ///
+    /// # Panics
+    /// Panics if `f` panics
+    pub fn par_map<I, T, F>(&mut self, items: Vec<I>, f: F) -> Result<Vec<T>>
+    where
+        I: Send,
+        T: Send,
+        F: Fn(&mut Self, I) -> Result<T> + Sync,
+    {
+        let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let chunk_size = items.len().div_ceil(threads).max(1);
+        let mut items = items.into_iter().peekable();
+        let mut chunks = Vec::new();
+        while items.peek().is_some() {
+            chunks.push((
+                self.child_ctx(),
+                items.by_ref().take(chunk_size).collect::<Vec<_>>(),
+            ));
+        }
+        let f = &f;
+        let chunks = std::thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|(mut ctx, chunk)| {
+                    scope.spawn(move || {
+                        let outputs: Vec<_> =
+                            chunk.into_iter().map(|item| f(&mut ctx, item)).collect();
+                        (ctx, outputs)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut outputs = Vec::new();
+        for (ctx, chunk_outputs) in chunks {
+            self.runtime_execution_time += ctx.runtime_execution_time;
+            self.leaves.extend(ctx.leaves);
+            outputs.extend(chunk_outputs);
+        }
+        outputs.into_iter().collect()
+    }
+
     fn report_progress(&self, stats: &Stats) {
         self.progress.report(ProgressReport {
             generation: self.generation,
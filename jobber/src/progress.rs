@@ -1,6 +1,7 @@
 use crate::Stats;
 
-pub trait Progress {
+/// Reported to from every thread jobs run on
+pub trait Progress: Sync {
     fn report(&self, report: ProgressReport);
 }
 
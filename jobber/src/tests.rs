@@ -1,4 +1,4 @@
-use std::{collections::HashMap, hash::Hash, num::NonZeroUsize};
+use std::{collections::HashMap, hash::Hash, num::NonZeroUsize, sync::Mutex};
 
 use anyhow::Result;
 
@@ -263,3 +263,62 @@ fn basic_job_args_macro() {
     parent_job(&mut cache.root_ctx(0, &()), &mut sys, 1).unwrap();
     assert_eq!(sys.count("parent_job"), 2);
 }
+
+#[test]
+fn par_map_child_job_deps() {
+    fn parent_job(ctx: &mut JobCtx<'_>, sys: &Mutex<CallCounter>) -> Result<Vec<usize>> {
+        ctx.job(JobId::new("parent_job", 0), |ctx: &mut JobCtx<'_>| {
+            println!("Run parent");
+            sys.lock().unwrap().inc("parent_job");
+            ctx.par_map((0..8).collect(), |ctx, x| child_job(ctx, sys, x))
+        })
+    }
+
+    fn child_job(ctx: &mut JobCtx<'_>, sys: &Mutex<CallCounter>, x: usize) -> Result<usize> {
+        ctx.job(JobId::new("child_job", x as u64), |ctx: &mut JobCtx<'_>| {
+            println!("Run child {x}");
+            sys.lock().unwrap().inc("child_job");
+            ctx.depends_file(format!("test_par_map_child_job_deps_{}.txt", x % 2))?;
+            Ok(x * 2)
+        })
+    }
+
+    std::fs::write("test_par_map_child_job_deps_0.txt", "abc").unwrap();
+    std::fs::write("test_par_map_child_job_deps_1.txt", "abc").unwrap();
+    let sys = Mutex::new(CallCounter::default());
+    let cache = crate::Cache::new(NonZeroUsize::new(16).unwrap());
+
+    let outputs = parent_job(&mut cache.root_ctx(0, &()), &sys).unwrap();
+    assert_eq!(outputs, [0, 2, 4, 6, 8, 10, 12, 14]);
+    parent_job(&mut cache.root_ctx(0, &()), &sys).unwrap();
+
+    assert_eq!(
+        sys.lock().unwrap().count("parent_job"),
+        1,
+        "parent_job called"
+    );
+    assert_eq!(
+        sys.lock().unwrap().count("child_job"),
+        8,
+        "child_job called"
+    );
+
+    std::fs::write("test_par_map_child_job_deps_1.txt", "abcdef").unwrap();
+
+    parent_job(&mut cache.root_ctx(0, &()), &sys).unwrap();
+    parent_job(&mut cache.root_ctx(0, &()), &sys).unwrap();
+
+    assert_eq!(
+        sys.lock().unwrap().count("parent_job"),
+        2,
+        "parent_job called"
+    );
+    assert_eq!(
+        sys.lock().unwrap().count("child_job"),
+        12,
+        "child_job called"
+    );
+
+    std::fs::remove_file("test_par_map_child_job_deps_0.txt").unwrap();
+    std::fs::remove_file("test_par_map_child_job_deps_1.txt").unwrap();
+}
@@ -1,9 +1,16 @@
-use std::{net::SocketAddr, num::NonZeroUsize, path::PathBuf, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
+use anyhow::Context as _;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 
-use crate::img_conversion::ImageConvertFormat;
+use crate::img_conversion::{ImageConvertFormat, ImagePreset};
 
 #[derive(Debug, Clone, Parser)]
 pub struct BuildConfig {
@@ -53,11 +60,186 @@ pub struct SiteConfig {
     pub convert_images: Vec<ImageConvertFormat>,
     pub details: Details,
     pub pages: PagesConfig,
+    #[serde(default)]
+    pub toc: TocConfig,
+    #[serde(default)]
+    pub markdown: MarkdownConfig,
+    #[serde(default)]
+    pub images: ImageConfig,
 }
 
 impl SiteConfig {
     pub fn validate(&self) -> anyhow::Result<()> {
-        self.pages.validate()
+        self.pages.validate()?;
+        self.toc.validate()?;
+        self.images.validate()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TocConfig {
+    /// The shallowest heading depth included in the table of contents
+    pub min_depth: u8,
+    /// The deepest heading depth included in the table of contents
+    pub max_depth: u8,
+}
+
+impl Default for TocConfig {
+    fn default() -> Self {
+        Self {
+            min_depth: 2,
+            max_depth: 4,
+        }
+    }
+}
+
+impl TocConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=6).contains(&self.min_depth)
+            || !(1..=6).contains(&self.max_depth)
+            || self.min_depth > self.max_depth
+        {
+            return Err(anyhow::anyhow!(
+                "toc depths {}..={} must be within 1..=6",
+                self.min_depth,
+                self.max_depth
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+#[expect(clippy::struct_excessive_bools)]
+pub struct MarkdownConfig {
+    /// `[^1]` footnote references with the definitions at the end of the post
+    pub footnotes: bool,
+    /// Blockquotes starting with a marker like `> [!NOTE]`, rendered as `<aside>`
+    pub callouts: bool,
+    /// Paragraphs of `Term` lines followed by `: Definition` lines, rendered as `<dl>`
+    pub definition_lists: bool,
+    /// Images alone in a paragraph with a title like `![alt](src "caption")`, as `<figure>`
+    pub figures: bool,
+    /// Figures link to the full size original image
+    pub link_figures: bool,
+}
+
+impl MarkdownConfig {
+    /// GitHub flavoured markdown with the enabled extensions, the same everywhere a post is parsed
+    pub fn parse_options(&self) -> markdown::ParseOptions {
+        let mut parse = markdown::ParseOptions::gfm();
+        parse.constructs.gfm_footnote_definition = self.footnotes;
+        parse.constructs.gfm_label_start_footnote = self.footnotes;
+        parse
+    }
+}
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        Self {
+            footnotes: true,
+            callouts: true,
+            definition_lists: true,
+            figures: true,
+            link_figures: false,
+        }
+    }
+}
+
+#[derive(Debug, Hash, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageConfig {
+    /// Widths in pixels of the smaller variants converted alongside each image's full size
+    pub widths: Vec<u32>,
+    /// The `sizes` attribute for images without their own or one from their preset
+    pub sizes: String,
+    /// The preset for images that do not select one
+    pub default_preset: String,
+    pub presets: BTreeMap<String, ImagePreset>,
+    /// Remove EXIF, XMP and comments from copied originals, except AVIF and JPEG XL
+    pub strip_metadata: bool,
+    /// The most bytes an image's fallback may be before it is warned about
+    pub max_bytes: Option<u64>,
+    /// Fail the build for images over `max_bytes` instead of warning
+    pub deny_over_budget: bool,
+    /// Fail on images without an alt attribute instead of warning, `alt=""` is decorative
+    pub deny_missing_alt: bool,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            widths: vec![400],
+            sizes: "(max-width: 840px) calc(100vw - 40px), 800px".to_owned(),
+            default_preset: "hero".to_owned(),
+            presets: BTreeMap::from([
+                ("hero".to_owned(), ImagePreset::default()),
+                ("thumb".to_owned(), ImagePreset::thumbnail()),
+            ]),
+            strip_metadata: true,
+            max_bytes: None,
+            deny_over_budget: false,
+            deny_missing_alt: false,
+        }
+    }
+}
+
+impl ImageConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.widths.contains(&0) {
+            return Err(anyhow::anyhow!("image widths must be greater than 0"));
+        }
+        if !self.presets.contains_key(&self.default_preset) {
+            return Err(anyhow::anyhow!(
+                "default image preset {:?} is not one of the presets",
+                self.default_preset
+            ));
+        }
+        // Preset names are part of the file names of converted images
+        if let Some(name) = self.presets.keys().find(|name| {
+            name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        }) {
+            return Err(anyhow::anyhow!(
+                "image preset name {name:?} must be letters, digits, - and _"
+            ));
+        }
+        if let Some((name, _)) = self
+            .presets
+            .iter()
+            .find(|(_, preset)| preset.size.0 == 0 || preset.size.1 == 0)
+        {
+            return Err(anyhow::anyhow!("image preset {name:?} has an empty size"));
+        }
+        Ok(())
+    }
+
+    /// The preset named by `name`, otherwise the first named in `class` or the default
+    pub fn preset<'a>(
+        &'a self,
+        name: Option<&str>,
+        class: &str,
+    ) -> anyhow::Result<(&'a str, &'a ImagePreset)> {
+        if let Some(name) = name {
+            return self
+                .presets
+                .get_key_value(name)
+                .map(|(name, preset)| (name.as_str(), preset))
+                .context(format!("unknown image preset {name:?}"));
+        }
+        let name = class
+            .split_whitespace()
+            .find(|c| self.presets.contains_key(*c))
+            .unwrap_or(&self.default_preset);
+        self.preset(Some(name), "")
+    }
+
+    pub fn sizes<'a>(&'a self, preset: &'a ImagePreset) -> &'a str {
+        preset.sizes.as_deref().unwrap_or(&self.sizes)
     }
 }
 
@@ -65,7 +247,19 @@ impl SiteConfig {
 pub struct PagesConfig {
     pub featured: Vec<String>,
     pub pages: Vec<String>,
+    /// Publish every `posts/*.md`, entries in `posts` are then only needed for overrides
+    #[serde(default)]
+    pub discover_posts: bool,
+    #[serde(default)]
     pub posts: Vec<PostConfig>,
+    pub pagination: Option<PaginationConfig>,
+    /// How many related posts to suggest at the end of each post
+    #[serde(default = "default_related_posts")]
+    pub related_posts: usize,
+}
+
+fn default_related_posts() -> usize {
+    3
 }
 
 impl PagesConfig {
@@ -74,13 +268,39 @@ impl PagesConfig {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaginationConfig {
+    pub posts_per_page: NonZeroUsize,
+    /// Pages whose post listing is split into pages, tag pages are always split
+    #[serde(default)]
+    pub pages: Vec<String>,
+}
+
 #[derive(Debug, Hash, Clone, Serialize, Deserialize)]
 pub struct PostConfig {
     pub slug: String,
     pub image: Option<String>,
+    /// Drafts are only rendered when serving and are never listed
+    #[serde(default)]
+    pub draft: bool,
+    /// The post is treated as a draft until this date
+    pub publish_after: Option<Date>,
 }
 
 impl PostConfig {
+    pub fn new(slug: String) -> Self {
+        Self {
+            slug,
+            image: None,
+            draft: false,
+            publish_after: None,
+        }
+    }
+
+    pub fn is_draft(&self, today: Date) -> bool {
+        self.draft || self.publish_after.is_some_and(|date| date > today)
+    }
+
     pub fn validate(&self) -> anyhow::Result<()> {
         if !self
             .slug
@@ -101,4 +321,83 @@ pub struct Details {
     pub name: String,
     pub email: String,
     pub summary: String,
+    /// The public base url such as `https://example.com`, without which there is no sitemap
+    pub url: Option<String>,
+}
+
+impl Details {
+    /// Join a site relative path onto the public base url, if there is one
+    pub fn absolute_url(&self, path: &str) -> String {
+        let base = self.url.as_deref().unwrap_or_default();
+        format!(
+            "{}/{}",
+            base.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+}
+
+/// Written in toml as a local date such as `2026-01-31`
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "toml::value::Datetime", into = "toml::value::Datetime")]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    /// # Panics
+    /// Panics if the system clock is set before the unix epoch
+    pub fn today() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("system time after unix epoch")
+            .as_secs();
+        Self::from_days_since_epoch(secs / 86400)
+    }
+
+    /// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+    fn from_days_since_epoch(days: u64) -> Self {
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+        Self {
+            year: u16::try_from(year).unwrap_or(u16::MAX),
+            month: u8::try_from(month).unwrap_or(u8::MAX),
+            day: u8::try_from(day).unwrap_or(u8::MAX),
+        }
+    }
+}
+
+impl TryFrom<toml::value::Datetime> for Date {
+    type Error = anyhow::Error;
+
+    fn try_from(value: toml::value::Datetime) -> Result<Self, Self::Error> {
+        let date = value.date.context("expected a date")?;
+        if value.time.is_some() || value.offset.is_some() {
+            return Err(anyhow::anyhow!("{value} must be a date without a time"));
+        }
+        Ok(Self {
+            year: date.year,
+            month: date.month,
+            day: date.day,
+        })
+    }
+}
+
+impl From<Date> for toml::value::Datetime {
+    fn from(Date { year, month, day }: Date) -> Self {
+        Self {
+            date: Some(toml::value::Date { year, month, day }),
+            time: None,
+            offset: None,
+        }
+    }
 }
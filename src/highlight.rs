@@ -56,6 +56,10 @@ const HIGHLIGHT_NAMES: &[&str; 52] = &[
     "variable.parameter",
 ];
 
+pub fn supports(language_name: &str) -> bool {
+    matches!(language_name, "rust" | "rain")
+}
+
 fn language_config(language_name: &str) -> Result<Option<HighlightConfiguration>> {
     Ok(match language_name {
         "rust" => Some(HighlightConfiguration::new(
@@ -99,8 +103,5 @@ pub fn src_to_highlight_html(language_name: &str, src: &str) -> Result<Option<St
         output.extend(b"'");
     })?;
 
-    let highlighted_html = std::str::from_utf8(&renderer.html)?;
-    Ok(Some(format!(
-        "<pre><code class=\"nohighlight tree-sitter\">{highlighted_html}</code></pre>"
-    )))
+    Ok(Some(std::str::from_utf8(&renderer.html)?.to_owned()))
 }
@@ -1,10 +1,9 @@
-use std::{ffi::OsStr, path::Path};
+use std::{ffi::OsStr, io::Write as _, path::Path};
 
 use anyhow::{Context as _, Result};
-use image::GenericImageView as _;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Hash, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ImageConvertFormat {
     Jxl,
     Webp,
@@ -27,10 +26,14 @@ impl ImageConvertFormat {
         }
     }
 
-    pub fn can_convert(self, dest: Self) -> bool {
+    /// Animated GIFs can only be converted to formats that can be animated, and every format can
+    /// be re-encoded as itself to optimise the `<img>` fallback
+    pub fn can_convert(self, dest: Self, animated: bool) -> bool {
         match (self, dest) {
-            (Self::Png | Self::Jpeg, Self::Jxl | Self::Webp | Self::Avif) => true,
-            // (Self::Gif, Self::Jxl) => true,
+            (Self::Png, Self::Jxl | Self::Webp | Self::Avif | Self::Png)
+            | (Self::Jpeg, Self::Jxl | Self::Webp | Self::Avif | Self::Jpeg)
+            | (Self::Gif, Self::Jxl | Self::Webp | Self::Gif) => true,
+            (Self::Gif, Self::Avif) => !animated,
             _ => false,
         }
     }
@@ -57,76 +60,535 @@ impl ImageConvertFormat {
         }
     }
 
+    /// Resize `src` to fill `size` and encode it as `self`
     pub fn convert(
         self,
         src: &Path,
-        target_cover_size: (u32, u32),
-        quality: Quality,
+        size: (u32, u32),
+        preset: &ImagePreset,
         dst: &Path,
-    ) -> Result<(u32, u32)> {
+    ) -> Result<()> {
         let src_format = Self::from_ext(src.extension().context("missing source extension")?)
             .context("invalid source extension")?;
-        debug_assert!(src_format.can_convert(self));
-        let img = image::ImageReader::open(src)?.decode()?;
-        let (width, height) = Self::calculate_img_size(img.dimensions(), target_cover_size);
+        debug_assert!(src_format.can_convert(self, preset.animated));
         if let Some(dir) = dst.parent() {
             std::fs::create_dir_all(dir)?;
         }
         let mut out = std::io::BufWriter::new(std::fs::File::create(dst)?);
-        let resized = img.resize_to_fill(width, height, image::imageops::FilterType::Lanczos3);
-        match (&src_format, self) {
-            (Self::Gif, Self::Jxl) => {
-                todo!("gif to jxl not implemented yet")
-            }
-            (_, Self::Jxl) => {
+        if matches!(src_format, Self::Gif) && preset.animated {
+            let frames = decode_gif_frames(src, size)?;
+            let encoded = match self {
+                Self::Jxl => {
+                    let frames: Vec<_> = frames
+                        .iter()
+                        .map(|(frame, duration)| jxl_encoder::AnimationFrame {
+                            pixels: frame.as_raw(),
+                            duration: *duration,
+                        })
+                        .collect();
+                    preset.jxl_encoder().encode_animation(
+                        size.0,
+                        size.1,
+                        jxl_encoder::PixelLayout::Rgba8,
+                        // Ticks of 1ms
+                        &jxl_encoder::AnimationParams {
+                            tps_numerator: 1000,
+                            tps_denominator: 1,
+                            num_loops: 0,
+                        },
+                        &frames,
+                    )?
+                }
+                Self::Webp => animated_webp(size, &frames)?,
+                Self::Gif => animated_gif(frames)?,
+                _ => anyhow::bail!("cannot encode an animated {}", self.extension()),
+            };
+            out.write_all(&encoded)?;
+            return Ok(());
+        }
+        let img = decode(src)?;
+        let resized = img.resize_to_fill(size.0, size.1, image::imageops::FilterType::Lanczos3);
+        match self {
+            Self::Jxl => {
                 let (pixels, layout) = if resized.has_alpha() {
                     (resized.to_rgba8().to_vec(), jxl_encoder::PixelLayout::Rgba8)
                 } else {
                     (resized.to_rgb8().to_vec(), jxl_encoder::PixelLayout::Rgb8)
                 };
-                let config = quality.jxl_encoder();
+                let config = preset.jxl_encoder();
                 let mut encoder = config.encoder(resized.width(), resized.height(), layout)?;
                 encoder.push_rows(&pixels, resized.height())?;
                 encoder.finish_to(&mut out)?;
             }
-            (_, Self::Webp) => {
+            Self::Webp => {
                 resized.write_to(&mut out, image::ImageFormat::WebP)?;
             }
-            (_, Self::Avif) => {
-                resized.write_to(&mut out, image::ImageFormat::Avif)?;
+            Self::Avif => {
+                // AVIF speed runs from 1, the slowest, to 10
+                let speed = 11 - preset.avif.effort.clamp(1, 10);
+                resized.write_with_encoder(
+                    image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                        &mut out,
+                        speed,
+                        preset.avif.quality,
+                    ),
+                )?;
+            }
+            Self::Png => {
+                resized.write_with_encoder(image::codecs::png::PngEncoder::new_with_quality(
+                    &mut out,
+                    image::codecs::png::CompressionType::Best,
+                    image::codecs::png::FilterType::Adaptive,
+                ))?;
+            }
+            Self::Jpeg => {
+                let mut encoder = jpeg_encoder::Encoder::new(&mut out, preset.jpeg_quality);
+                encoder.set_progressive(preset.progressive);
+                encoder.set_optimized_huffman_tables(true);
+                encoder.encode(
+                    resized.to_rgb8().as_raw(),
+                    u16::try_from(resized.width())?,
+                    u16::try_from(resized.height())?,
+                    jpeg_encoder::ColorType::Rgb,
+                )?;
+            }
+            Self::Gif => {
+                resized.write_to(&mut out, image::ImageFormat::Gif)?;
             }
-            (_, Self::Png | Self::Jpeg | Self::Gif) => todo!(),
         }
-        Ok((width, height))
+        Ok(())
     }
+}
+
+/// Decode `src` upright and in sRGB
+pub fn decode(src: &Path) -> Result<image::DynamicImage> {
+    use image::ImageDecoder as _;
 
-    fn calculate_img_size(actual_size: (u32, u32), target_cover_size: (u32, u32)) -> (u32, u32) {
-        let (w, h) = actual_size;
-        let new_w = w.min(target_cover_size.0);
-        let new_h = new_w * h / w;
-        let new_new_h = h.min(new_h);
-        let new_new_w = new_new_h * new_w / new_h;
-        (new_new_w, new_new_h)
+    let mut decoder = image::ImageReader::open(src)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let icc_profile = decoder.icc_profile()?;
+    let mut img = image::DynamicImage::from_decoder(decoder)?;
+    if let Some(icc_profile) = icc_profile {
+        img = to_srgb(img, &icc_profile).context(format!("convert {src:?} to sRGB"))?;
     }
+    img.apply_orientation(orientation);
+    Ok(img)
 }
 
-#[derive(Clone, Copy)]
-pub enum Quality {
-    Thumbnail,
-    Hero,
+/// The size of `src` once upright
+pub fn dimensions(src: &Path) -> Result<(u32, u32)> {
+    use image::metadata::Orientation;
+
+    let (width, height) = image::image_dimensions(src)?;
+    Ok(match orientation(src)? {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    })
 }
 
-impl Quality {
-    fn jxl_encoder(self) -> jxl_encoder::LossyConfig {
-        match self {
-            Self::Thumbnail => jxl_encoder::LossyConfig::new(2.4)
-                .with_mode(jxl_encoder::EncoderMode::Experimental)
-                .with_effort(10)
-                .with_progressive(jxl_encoder::ProgressiveMode::Single),
-            Self::Hero => jxl_encoder::LossyConfig::new(1.0)
-                .with_mode(jxl_encoder::EncoderMode::Experimental)
-                .with_effort(10)
-                .with_progressive(jxl_encoder::ProgressiveMode::DcVlfLfAc),
+pub fn orientation(src: &Path) -> Result<image::metadata::Orientation> {
+    use image::ImageDecoder as _;
+
+    Ok(image::ImageReader::open(src)?
+        .with_guessed_format()?
+        .into_decoder()?
+        .orientation()?)
+}
+
+/// Convert from an RGB ICC profile to sRGB, other profiles are left as is
+fn to_srgb(img: image::DynamicImage, icc_profile: &[u8]) -> Result<image::DynamicImage> {
+    let profile = moxcms::ColorProfile::new_from_slice(icc_profile)?;
+    if profile.color_space != moxcms::DataColorSpace::Rgb {
+        return Ok(img);
+    }
+    let layout = if img.color().has_alpha() {
+        moxcms::Layout::Rgba
+    } else {
+        moxcms::Layout::Rgb
+    };
+    let transform = profile.create_transform_8bit(
+        layout,
+        &moxcms::ColorProfile::new_srgb(),
+        layout,
+        moxcms::TransformOptions::default(),
+    )?;
+    let (width, height) = (img.width(), img.height());
+    Ok(if img.color().has_alpha() {
+        let src = img.to_rgba8();
+        let mut dst = vec![0; src.len()];
+        transform.transform(&src, &mut dst)?;
+        image::RgbaImage::from_raw(width, height, dst)
+            .context("sRGB image size")?
+            .into()
+    } else {
+        let src = img.to_rgb8();
+        let mut dst = vec![0; src.len()];
+        transform.transform(&src, &mut dst)?;
+        image::RgbImage::from_raw(width, height, dst)
+            .context("sRGB image size")?
+            .into()
+    })
+}
+
+/// GIF frames resized to `size`, each with its duration in milliseconds
+fn decode_gif_frames(src: &Path, size: (u32, u32)) -> Result<Vec<(image::RgbaImage, u32)>> {
+    use image::AnimationDecoder as _;
+
+    let file = std::io::BufReader::new(std::fs::File::open(src)?);
+    image::codecs::gif::GifDecoder::new(file)?
+        .into_frames()
+        .map(|frame| {
+            let frame = frame?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            // Browsers show tiny GIF delays as 100ms, so keep the timing they play with
+            let duration = match numer / denom.max(1) {
+                ..20 => 100,
+                duration => duration,
+            };
+            let resized = image::DynamicImage::from(frame.into_buffer()).resize_to_fill(
+                size.0,
+                size.1,
+                image::imageops::FilterType::Lanczos3,
+            );
+            Ok((resized.to_rgba8(), duration))
+        })
+        .collect()
+}
+
+/// Shown while an image loads
+#[derive(Debug, Clone)]
+pub struct Placeholder {
+    /// `#rrggbb`, unless the image is fully transparent
+    pub color: Option<String>,
+    /// A tiny `data:` URI, unless the image is partly transparent so it would show through
+    pub preview: Option<String>,
+}
+
+impl Placeholder {
+    const PREVIEW_WIDTH: u32 = 16;
+    const SAMPLE_WIDTH: u32 = 32;
+
+    pub fn new(src: &Path, size: (u32, u32)) -> Result<Self> {
+        use base64::Engine as _;
+
+        let img = decode(src)?;
+        let scaled = |width: u32| {
+            let height = (size.1 * width / size.0).max(1);
+            img.resize_to_fill(width, height, image::imageops::FilterType::Triangle)
+                .to_rgba8()
+        };
+        let sample = scaled(Self::SAMPLE_WIDTH);
+        let color = dominant_color(&sample).map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"));
+        if sample.pixels().any(|p| p[3] < u8::MAX) {
+            return Ok(Self {
+                color,
+                preview: None,
+            });
+        }
+        let preview = image::DynamicImage::from(scaled(Self::PREVIEW_WIDTH)).to_rgb8();
+        let mut webp = Vec::new();
+        image::codecs::webp::WebPEncoder::new_lossless(&mut webp).encode(
+            preview.as_raw(),
+            preview.width(),
+            preview.height(),
+            image::ExtendedColorType::Rgb8,
+        )?;
+        Ok(Self {
+            color,
+            preview: Some(format!(
+                "data:image/webp;base64,{}",
+                base64::engine::general_purpose::STANDARD.encode(webp)
+            )),
+        })
+    }
+}
+
+/// The average of the most common colour of the mostly opaque pixels, bucketed to 4 bits a channel
+fn dominant_color(img: &image::RgbaImage) -> Option<[u8; 3]> {
+    let mut buckets = std::collections::HashMap::<[u8; 3], (u32, [u32; 3])>::new();
+    for &image::Rgba([r, g, b, a]) in img.pixels() {
+        if a < 128 {
+            continue;
+        }
+        let (count, sum) = buckets.entry([r >> 4, g >> 4, b >> 4]).or_default();
+        *count += 1;
+        for (total, channel) in sum.iter_mut().zip([r, g, b]) {
+            *total += u32::from(channel);
+        }
+    }
+    let (_, (count, sum)) = buckets
+        .into_iter()
+        .max_by_key(|&(bucket, (count, _))| (count, bucket))?;
+    Some(sum.map(|total| u8::try_from(total / count).unwrap_or(u8::MAX)))
+}
+
+/// A looping GIF of the frames, each quantised to its own palette
+fn animated_gif(frames: Vec<(image::RgbaImage, u32)>) -> Result<Vec<u8>> {
+    let mut gif = Vec::new();
+    let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+    encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
+    encoder.encode_frames(frames.into_iter().map(|(frame, duration)| {
+        image::Frame::from_parts(frame, 0, 0, image::Delay::from_numer_denom_ms(duration, 1))
+    }))?;
+    drop(encoder);
+    Ok(gif)
+}
+
+/// `(x, y, width, height)` in pixels
+type Rect = (u32, u32, u32, u32);
+
+/// An animated WebP muxed from lossless stills, as the `image` crate only encodes still WebP
+fn animated_webp(size: (u32, u32), frames: &[(image::RgbaImage, u32)]) -> Result<Vec<u8>> {
+    fn chunk(out: &mut Vec<u8>, fourcc: [u8; 4], data: &[u8]) -> Result<()> {
+        out.extend_from_slice(&fourcc);
+        out.extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+        Ok(())
+    }
+    fn u24(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&value.to_le_bytes()[..3]);
+    }
+
+    // The changed rectangle of each frame, unchanged frames lengthen the last
+    let mut parts: Vec<(&image::RgbaImage, Rect, u32)> = Vec::new();
+    let mut previous = None;
+    for (frame, duration) in frames {
+        let rect = match previous {
+            Some(previous) => changed_rect(previous, frame),
+            None => Some((0, 0, size.0, size.1)),
+        };
+        match (rect, parts.last_mut()) {
+            (Some(rect), _) => parts.push((frame, rect, *duration)),
+            (None, Some((_, _, last_duration))) => *last_duration += duration,
+            (None, None) => {}
+        }
+        previous = Some(frame);
+    }
+
+    let mut body = b"WEBP".to_vec();
+    let mut vp8x = vec![0b0001_0010, 0, 0, 0];
+    u24(&mut vp8x, size.0 - 1);
+    u24(&mut vp8x, size.1 - 1);
+    chunk(&mut body, *b"VP8X", &vp8x)?;
+    // A transparent background, looping forever
+    chunk(&mut body, *b"ANIM", &[0, 0, 0, 0, 0, 0])?;
+    for (frame, (x, y, width, height), duration) in parts {
+        let part = image::imageops::crop_imm(frame, x, y, width, height).to_image();
+        let mut still = Vec::new();
+        image::codecs::webp::WebPEncoder::new_lossless(&mut still).encode(
+            part.as_raw(),
+            width,
+            height,
+            image::ExtendedColorType::Rgba8,
+        )?;
+        let mut anmf = Vec::new();
+        u24(&mut anmf, x / 2);
+        u24(&mut anmf, y / 2);
+        u24(&mut anmf, width - 1);
+        u24(&mut anmf, height - 1);
+        u24(&mut anmf, duration);
+        // Replace the rectangle instead of blending with the frame before
+        anmf.push(0b10);
+        // Skip the RIFF header then copy the ALPH, VP8 and VP8L chunks
+        let mut chunks = still.get(12..).context("webp header")?;
+        while let [a, b, c, d, l0, l1, l2, l3, rest @ ..] = chunks {
+            let len = u32::from_le_bytes([*l0, *l1, *l2, *l3]) as usize;
+            let padded = (len + 1) & !1;
+            let data = rest.get(..len).context("webp chunk length")?;
+            if matches!(&[*a, *b, *c, *d], b"ALPH" | b"VP8 " | b"VP8L") {
+                chunk(&mut anmf, [*a, *b, *c, *d], data)?;
+            }
+            chunks = rest.get(padded..).unwrap_or_default();
+        }
+        chunk(&mut body, *b"ANMF", &anmf)?;
+    }
+    let mut webp = Vec::with_capacity(body.len() + 8);
+    chunk(&mut webp, *b"RIFF", &body)?;
+    Ok(webp)
+}
+
+/// The smallest rectangle of changed pixels, on even offsets as WebP frames require
+fn changed_rect(previous: &image::RgbaImage, frame: &image::RgbaImage) -> Option<Rect> {
+    let mut min = (u32::MAX, u32::MAX);
+    let mut max = (0, 0);
+    for ((x, y, a), b) in previous.enumerate_pixels().zip(frame.pixels()) {
+        if a != b {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+    }
+    if min.0 > max.0 {
+        return None;
+    }
+    let (x, y) = (min.0 & !1, min.1 & !1);
+    Some((x, y, max.0 + 1 - x, max.1 + 1 - y))
+}
+
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFit {
+    /// Scale down to fit within the size, keeping the whole image
+    Contain,
+    /// Scale down and crop to fill the size exactly
+    Cover,
+}
+
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncodeQuality {
+    /// From 0 to 100
+    pub quality: u8,
+    /// From 1, the fastest, to 10, the smallest
+    pub effort: u8,
+}
+
+/// Selected for an image by its class or a `#preset=name` fragment
+///
+/// WebP and PNG are encoded losslessly so have no settings, and AVIF cannot be animated.
+#[derive(Debug, Hash, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImagePreset {
+    /// The largest width and height converted images are scaled down to
+    pub size: (u32, u32),
+    pub fit: ImageFit,
+    /// Overrides the site wide `sizes` attribute
+    pub sizes: Option<String>,
+    pub jxl: EncodeQuality,
+    pub avif: EncodeQuality,
+    /// From 0 to 100, for the JPEG `<img>` fallback
+    pub jpeg_quality: u8,
+    /// Encode JPEG XL and JPEG in passes so a blurry preview shows while they load
+    pub progressive: bool,
+    /// Keep the animation of GIFs, otherwise their first frame is used as a still poster
+    pub animated: bool,
+}
+
+impl Default for ImagePreset {
+    fn default() -> Self {
+        Self {
+            size: (800, 800),
+            fit: ImageFit::Contain,
+            sizes: None,
+            // Butteraugli distance 1.0 under `jxl_encoder::quality_to_distance`
+            jxl: EncodeQuality {
+                quality: 90,
+                effort: 10,
+            },
+            avif: EncodeQuality {
+                quality: 80,
+                effort: 7,
+            },
+            jpeg_quality: 85,
+            progressive: true,
+            animated: true,
+        }
+    }
+}
+
+impl ImagePreset {
+    pub fn thumbnail() -> Self {
+        Self {
+            size: (240, 130),
+            fit: ImageFit::Cover,
+            sizes: Some("250px".to_owned()),
+            // Butteraugli distance 2.4 under `jxl_encoder::quality_to_distance`
+            jxl: EncodeQuality {
+                quality: 66,
+                effort: 10,
+            },
+            progressive: false,
+            animated: false,
+            ..Self::default()
+        }
+    }
+
+    /// The size an image of `actual_size` is converted to, never scaled up
+    #[expect(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn fit_size(&self, actual_size: (u32, u32)) -> (u32, u32) {
+        let (w, h) = (f64::from(actual_size.0), f64::from(actual_size.1));
+        let (target_w, target_h) = (f64::from(self.size.0), f64::from(self.size.1));
+        let (w, h) = match self.fit {
+            ImageFit::Contain => {
+                let scale = (target_w / w).min(target_h / h).min(1.0);
+                (w * scale, h * scale)
+            }
+            ImageFit::Cover => {
+                let scale = (w / target_w).min(h / target_h).min(1.0);
+                (target_w * scale, target_h * scale)
+            }
+        };
+        (w.round().max(1.0) as u32, h.round().max(1.0) as u32)
+    }
+
+    fn jxl_encoder(&self) -> jxl_encoder::LossyConfig {
+        let progressive = if self.progressive {
+            jxl_encoder::ProgressiveMode::DcVlfLfAc
+        } else {
+            jxl_encoder::ProgressiveMode::Single
+        };
+        jxl_encoder::LossyConfig::new(jxl_encoder::quality_to_distance(f32::from(
+            self.jxl.quality,
+        )))
+        .with_mode(jxl_encoder::EncoderMode::Experimental)
+        .with_effort(self.jxl.effort)
+        .with_progressive(progressive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{AnimationDecoder as _, Rgba, RgbaImage};
+
+    use super::{animated_webp, changed_rect};
+
+    #[test]
+    fn changed_rects() {
+        let a = RgbaImage::from_pixel(6, 6, Rgba([255, 0, 0, 255]));
+        let mut b = a.clone();
+        assert_eq!(changed_rect(&a, &b), None);
+        b.put_pixel(3, 3, Rgba([0, 0, 255, 255]));
+        b.put_pixel(4, 5, Rgba([0, 0, 255, 255]));
+        // Offsets are rounded down to even coordinates
+        assert_eq!(changed_rect(&a, &b), Some((2, 2, 3, 4)));
+    }
+
+    #[test]
+    fn animated_webp_frames() {
+        let first = RgbaImage::from_pixel(6, 6, Rgba([255, 0, 0, 255]));
+        let mut second = first.clone();
+        for (x, y) in [(3, 3), (4, 3), (3, 4)] {
+            second.put_pixel(x, y, Rgba([0, 0, 255, 128]));
+        }
+        let mut third = second.clone();
+        third.put_pixel(0, 5, Rgba([0, 255, 0, 255]));
+        let frames = [
+            (first.clone(), 100),
+            (second.clone(), 50),
+            // Unchanged, so it lengthens the frame before
+            (second.clone(), 30),
+            (third.clone(), 200),
+        ];
+        let webp = animated_webp((6, 6), &frames).unwrap();
+
+        let decoded = image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(webp))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        let delays: Vec<_> = decoded
+            .iter()
+            .map(|frame| frame.delay().numer_denom_ms())
+            .collect();
+        assert_eq!(delays, [(100, 1), (80, 1), (200, 1)]);
+        for (frame, expected) in decoded.iter().zip([first, second, third]) {
+            assert_eq!(frame.buffer(), &expected);
         }
     }
 }
@@ -0,0 +1,428 @@
+use anyhow::{Context as _, Result, bail};
+
+use crate::img_conversion::ImageConvertFormat;
+
+/// Remove EXIF, XMP, IPTC and comments, keeping ICC profiles and any orientation other than `1`
+pub fn strip(bytes: &[u8], format: ImageConvertFormat, orientation: u8) -> Result<Vec<u8>> {
+    match format {
+        ImageConvertFormat::Jpeg => strip_jpeg(bytes, orientation).context("strip JPEG metadata"),
+        ImageConvertFormat::Png => strip_png(bytes, orientation).context("strip PNG metadata"),
+        ImageConvertFormat::Gif => strip_gif(bytes).context("strip GIF metadata"),
+        ImageConvertFormat::Webp => strip_webp(bytes, orientation).context("strip WebP metadata"),
+        ImageConvertFormat::Avif | ImageConvertFormat::Jxl => {
+            bail!("cannot strip metadata from {}", format.extension())
+        }
+    }
+}
+
+/// EXIF with only the orientation tag
+fn exif_orientation(orientation: u8) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    // Tag, SHORT type, count and the value padded to 4 bytes
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&[0, orientation, 0, 0]);
+    // No next IFD
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff
+}
+
+/// Keep the JFIF, ICC profile and Adobe colour transform segments and the image data
+fn strip_jpeg(bytes: &[u8], orientation: u8) -> Result<Vec<u8>> {
+    const APP0: u8 = 0xe0;
+    const APP2: u8 = 0xe2;
+    const APP14: u8 = 0xee;
+    const SOS: u8 = 0xda;
+
+    let mut rest = bytes
+        .strip_prefix(&[0xff, 0xd8])
+        .context("missing start of image")?;
+    let mut out = vec![0xff, 0xd8];
+    let mut exif = (orientation != 1).then(|| {
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend(exif_orientation(orientation));
+        payload
+    });
+    loop {
+        let &[0xff, marker, ..] = rest else {
+            bail!("expected a marker at offset {}", bytes.len() - rest.len());
+        };
+        // Fill bytes before a marker
+        if marker == 0xff {
+            rest = &rest[1..];
+            continue;
+        }
+        // EXIF goes after JFIF if there is one, otherwise first
+        if marker != APP0
+            && let Some(payload) = exif.take()
+        {
+            out.extend_from_slice(&[0xff, 0xe1]);
+            out.extend_from_slice(&u16::try_from(payload.len() + 2)?.to_be_bytes());
+            out.extend(payload);
+        }
+        // Scans are followed by entropy coded data without lengths, which continues to the end
+        if marker == SOS {
+            out.extend_from_slice(rest);
+            return Ok(out);
+        }
+        if matches!(marker, 0x01 | 0xd0..=0xd7) {
+            out.extend_from_slice(&rest[..2]);
+            rest = &rest[2..];
+            continue;
+        }
+        let len = usize::from(u16::from_be_bytes([
+            *rest.get(2).context("truncated segment")?,
+            *rest.get(3).context("truncated segment")?,
+        ]));
+        let segment = rest.get(..2 + len).context("truncated segment")?;
+        let keep = match marker {
+            APP0 | APP14 => true,
+            APP2 => segment
+                .get(4..)
+                .is_some_and(|data| data.starts_with(b"ICC_PROFILE\0")),
+            // Other application segments and comments
+            0xe1..=0xef | 0xfe => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(segment);
+        }
+        rest = &rest[2 + len..];
+    }
+}
+
+/// Drop the text, time and EXIF chunks
+fn strip_png(bytes: &[u8], orientation: u8) -> Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    let mut rest = bytes
+        .strip_prefix(SIGNATURE)
+        .context("missing PNG signature")?;
+    let mut out = SIGNATURE.to_vec();
+    let mut exif = (orientation != 1).then(|| exif_orientation(orientation));
+    while !rest.is_empty() {
+        let len = usize::try_from(u32::from_be_bytes(
+            rest.get(..4).context("truncated chunk")?.try_into()?,
+        ))?;
+        let chunk = rest.get(..12 + len).context("truncated chunk")?;
+        let kind = &chunk[4..8];
+        if kind == b"IDAT"
+            && let Some(exif) = exif.take()
+        {
+            out.extend_from_slice(&u32::try_from(exif.len())?.to_be_bytes());
+            let start = out.len();
+            out.extend_from_slice(b"eXIf");
+            out.extend(exif);
+            let crc = crc32(&out[start..]);
+            out.extend_from_slice(&crc.to_be_bytes());
+        }
+        if !matches!(kind, b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME") {
+            out.extend_from_slice(chunk);
+        }
+        rest = &rest[12 + len..];
+    }
+    Ok(out)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(u32::MAX, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Drop the EXIF and XMP chunks and their flags in the extended header
+fn strip_webp(bytes: &[u8], orientation: u8) -> Result<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+
+    let header = bytes.get(..12).context("truncated RIFF header")?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WEBP" {
+        bail!("missing WebP signature");
+    }
+    let mut rest = &bytes[12..];
+    let mut chunks = Vec::new();
+    let mut extended = false;
+    while !rest.is_empty() {
+        let len = usize::try_from(u32::from_le_bytes(
+            rest.get(4..8).context("truncated chunk")?.try_into()?,
+        ))?;
+        // Chunks are padded to an even length
+        let padded = 8 + len + len % 2;
+        let mut chunk = rest
+            .get(..padded)
+            .or_else(|| rest.get(..8 + len))
+            .context("truncated chunk")?
+            .to_vec();
+        rest = &rest[chunk.len()..];
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => continue,
+            b"VP8X" => {
+                extended = true;
+                *chunk.get_mut(8).context("truncated VP8X chunk")? &= !(EXIF_FLAG | XMP_FLAG);
+            }
+            _ => {}
+        }
+        chunks.push(chunk);
+    }
+    // Only the extended format can have EXIF, which goes after the image data
+    if orientation != 1 && extended {
+        let exif = exif_orientation(orientation);
+        let mut chunk = b"EXIF".to_vec();
+        chunk.extend_from_slice(&u32::try_from(exif.len())?.to_le_bytes());
+        chunk.extend(exif);
+        chunk.resize(chunk.len() + chunk.len() % 2, 0);
+        if let Some(vp8x) = chunks.iter_mut().find(|chunk| chunk.starts_with(b"VP8X")) {
+            vp8x[8] |= EXIF_FLAG;
+        }
+        chunks.push(chunk);
+    }
+    let body = chunks.concat();
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&u32::try_from(4 + body.len())?.to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend(body);
+    Ok(out)
+}
+
+/// Drop comments and every application extension but the ones that loop animations
+fn strip_gif(bytes: &[u8]) -> Result<Vec<u8>> {
+    fn sub_blocks_len(bytes: &[u8]) -> Result<usize> {
+        let mut pos = 0;
+        loop {
+            let len = usize::from(*bytes.get(pos).context("truncated sub-blocks")?);
+            pos += 1 + len;
+            if len == 0 {
+                return Ok(pos);
+            }
+        }
+    }
+
+    fn color_table_len(packed: u8) -> usize {
+        if packed & 0x80 == 0 {
+            0
+        } else {
+            3 << ((packed & 0x07) + 1)
+        }
+    }
+
+    let header = bytes.get(..13).context("truncated GIF header")?;
+    if !header.starts_with(b"GIF") {
+        bail!("missing GIF signature");
+    }
+    let mut pos = 13 + color_table_len(header[10]);
+    let mut out = bytes.get(..pos).context("truncated color table")?.to_vec();
+    loop {
+        let start = pos;
+        match bytes.get(pos).context("missing GIF trailer")? {
+            0x21 => {
+                let label = *bytes.get(pos + 1).context("truncated extension")?;
+                pos += 2;
+                let keep = match label {
+                    // Comment
+                    0xfe => false,
+                    // Application
+                    0xff => {
+                        let id = bytes
+                            .get(pos + 1..pos + 12)
+                            .context("truncated extension")?;
+                        id == b"NETSCAPE2.0" || id == b"ANIMEXTS1.0"
+                    }
+                    _ => true,
+                };
+                pos += sub_blocks_len(&bytes[pos..])?;
+                if keep {
+                    out.extend_from_slice(&bytes[start..pos]);
+                }
+            }
+            0x2c => {
+                let packed = *bytes.get(pos + 9).context("truncated image descriptor")?;
+                // Descriptor, colour table and LZW minimum code size
+                pos += 10 + color_table_len(packed) + 1;
+                pos += sub_blocks_len(bytes.get(pos..).context("truncated image")?)?;
+                out.extend_from_slice(&bytes[start..pos]);
+            }
+            0x3b => {
+                out.push(0x3b);
+                return Ok(out);
+            }
+            block => bail!("unknown GIF block {block:#04x} at offset {pos}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, exif_orientation, strip_gif, strip_jpeg, strip_png, strip_webp};
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let len = u16::try_from(payload.len() + 2).unwrap();
+        [&[0xff, marker][..], &len.to_be_bytes(), payload].concat()
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let len = u32::try_from(data.len()).unwrap();
+        let crc = crc32(&[kind, data].concat());
+        [&len.to_be_bytes()[..], kind, data, &crc.to_be_bytes()].concat()
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let len = u32::try_from(data.len()).unwrap();
+        let pad: &[u8] = if data.len() % 2 == 1 { &[0] } else { &[] };
+        [kind, &len.to_le_bytes(), data, pad].concat()
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = chunks.concat();
+        let len = u32::try_from(body.len() + 4).unwrap();
+        [&b"RIFF"[..], &len.to_le_bytes(), b"WEBP", &body].concat()
+    }
+
+    fn check_truncated(bytes: &[u8], strip: impl Fn(&[u8]) -> anyhow::Result<Vec<u8>>) {
+        for len in 0..bytes.len() {
+            _ = strip(&bytes[..len]);
+        }
+    }
+
+    #[test]
+    fn jpeg() {
+        let app0 = jpeg_segment(0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        let icc = jpeg_segment(0xe2, b"ICC_PROFILE\0\x01\x01profile");
+        let dqt = jpeg_segment(0xdb, b"\0table");
+        let scan = [
+            jpeg_segment(0xda, b"\x01\x01\0\0\x3f\0"),
+            b"\x12\xff\0\x34\xff\xd9".to_vec(),
+        ]
+        .concat();
+        let bytes = [
+            b"\xff\xd8".to_vec(),
+            app0.clone(),
+            jpeg_segment(0xe1, b"Exif\0\0GPS"),
+            jpeg_segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x>GPS</x>"),
+            icc.clone(),
+            jpeg_segment(0xfe, b"GPS"),
+            dqt.clone(),
+            scan.clone(),
+        ]
+        .concat();
+        let exif = jpeg_segment(0xe1, &[&b"Exif\0\0"[..], &exif_orientation(6)].concat());
+        assert_eq!(
+            strip_jpeg(&bytes, 6).unwrap(),
+            [
+                b"\xff\xd8".to_vec(),
+                app0.clone(),
+                exif,
+                icc.clone(),
+                dqt.clone(),
+                scan.clone()
+            ]
+            .concat()
+        );
+        assert_eq!(
+            strip_jpeg(&bytes, 1).unwrap(),
+            [b"\xff\xd8".to_vec(), app0, icc, dqt, scan].concat()
+        );
+        assert!(strip_jpeg(&bytes[..10], 1).is_err());
+        check_truncated(&bytes, |bytes| strip_jpeg(bytes, 6));
+    }
+
+    #[test]
+    fn png() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        let signature = b"\x89PNG\r\n\x1a\n".to_vec();
+        let ihdr = png_chunk(b"IHDR", b"\0\0\0\x01\0\0\0\x01\x08\x02\0\0\0");
+        let idat = png_chunk(b"IDAT", b"data");
+        let iend = png_chunk(b"IEND", b"");
+        let bytes = [
+            signature.clone(),
+            ihdr.clone(),
+            png_chunk(b"tEXt", b"Comment\0GPS"),
+            png_chunk(b"eXIf", b"MM\0\x2aGPS"),
+            idat.clone(),
+            iend.clone(),
+        ]
+        .concat();
+        let stripped = strip_png(&bytes, 6).unwrap();
+        let exif = png_chunk(b"eXIf", &exif_orientation(6));
+        assert_eq!(exif[exif.len() - 4..], 0xd667_4b69u32.to_be_bytes());
+        assert_eq!(
+            stripped,
+            [
+                signature.clone(),
+                ihdr.clone(),
+                exif,
+                idat.clone(),
+                iend.clone()
+            ]
+            .concat()
+        );
+        assert_eq!(
+            strip_png(&bytes, 1).unwrap(),
+            [signature, ihdr, idat, iend].concat()
+        );
+        assert!(strip_png(&bytes[..bytes.len() - 3], 1).is_err());
+        check_truncated(&bytes, |bytes| strip_png(bytes, 6));
+    }
+
+    #[test]
+    fn webp_extended() {
+        // Alpha, EXIF and XMP flags and a 1x1 canvas
+        let vp8x = |flags| webp_chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let image = webp_chunk(b"VP8L", b"odd");
+        let bytes = webp(&[
+            vp8x(0x1c),
+            image.clone(),
+            webp_chunk(b"EXIF", b"MM\0\x2aGPS"),
+            webp_chunk(b"XMP ", b"<x>GPS</x>"),
+        ]);
+        assert_eq!(
+            strip_webp(&bytes, 1).unwrap(),
+            webp(&[vp8x(0x10), image.clone()])
+        );
+        assert_eq!(
+            strip_webp(&bytes, 6).unwrap(),
+            webp(&[vp8x(0x18), image, webp_chunk(b"EXIF", &exif_orientation(6))])
+        );
+        assert!(strip_webp(&bytes[..bytes.len() - 3], 1).is_err());
+        check_truncated(&bytes, |bytes| strip_webp(bytes, 6));
+    }
+
+    #[test]
+    fn webp_simple() {
+        // Without VP8X there is nowhere to flag EXIF so the orientation is not written
+        let bytes = webp(&[webp_chunk(b"VP8 ", b"data")]);
+        assert_eq!(strip_webp(&bytes, 6).unwrap(), bytes);
+    }
+
+    #[test]
+    fn gif() {
+        // A 1x1 screen without a global colour table
+        let header = b"GIF89a\x01\0\x01\0\0\0\0".to_vec();
+        let netscape = b"\x21\xff\x0bNETSCAPE2.0\x03\x01\0\0\0".to_vec();
+        let control = b"\x21\xf9\x04\0\0\0\0\0".to_vec();
+        let image = b"\x2c\0\0\0\0\x01\0\x01\0\0\x02\x02\x44\x01\0".to_vec();
+        let bytes = [
+            header.clone(),
+            netscape.clone(),
+            b"\x21\xff\x0bXMP DataXMP\x03GPS\0".to_vec(),
+            b"\x21\xfe\x03GPS\0".to_vec(),
+            control.clone(),
+            image.clone(),
+            b"\x3b".to_vec(),
+        ]
+        .concat();
+        assert_eq!(
+            strip_gif(&bytes).unwrap(),
+            [header, netscape, control, image, b"\x3b".to_vec()].concat()
+        );
+        assert!(strip_gif(&bytes[..bytes.len() - 1]).is_err());
+        check_truncated(&bytes, strip_gif);
+    }
+}
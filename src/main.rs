@@ -3,13 +3,22 @@ use clap::{Parser, Subcommand};
 use site::Site;
 
 mod config;
+mod markdown_ext;
 mod minify;
 mod post;
 mod progress;
+mod related;
+mod rewrite;
+mod search;
+mod shortcode;
 mod site;
+mod sitemap;
+mod social_card;
+mod toc;
 
 mod highlight;
 mod img_conversion;
+mod img_metadata;
 #[cfg(feature = "server")]
 mod server;
 
@@ -31,7 +40,7 @@ fn main() -> Result<()> {
     match cli.command {
         Command::Build(config) => {
             let cache = jobber::Cache::new(config.build_cache_size);
-            Site::new(config, false).build_site_with_cache(&cache)?;
+            Site::new(config, false, false).build_site_with_cache(&cache)?;
         }
         Command::Serve(config) => {
             #[cfg(feature = "server")]
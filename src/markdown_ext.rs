@@ -0,0 +1,193 @@
+use std::{fmt::Write as _, ops::Range};
+
+use markdown::mdast::Node;
+
+use crate::rewrite::escape;
+
+/// GitHub style alerts, written as a blockquote starting with a marker like `> [!NOTE]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Callout {
+    Note,
+    Tip,
+    Important,
+    Warning,
+    Caution,
+}
+
+impl Callout {
+    const ALL: [Self; 5] = [
+        Self::Note,
+        Self::Tip,
+        Self::Important,
+        Self::Warning,
+        Self::Caution,
+    ];
+
+    /// Split off a leading `[!NOTE]` style marker, matched case insensitively
+    pub fn strip_marker(text: &str) -> Option<(Self, &str)> {
+        let (marker, rest) = text.strip_prefix("[!")?.split_once(']')?;
+        let callout = Self::ALL
+            .into_iter()
+            .find(|c| c.title().eq_ignore_ascii_case(marker))?;
+        if !rest.is_empty() && !rest.starts_with(['\n', '\r']) {
+            return None;
+        }
+        Some((callout, rest.trim_start_matches(['\n', '\r'])))
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Note => "Note",
+            Self::Tip => "Tip",
+            Self::Important => "Important",
+            Self::Warning => "Warning",
+            Self::Caution => "Caution",
+        }
+    }
+
+    pub fn class(self) -> &'static str {
+        match self {
+            Self::Note => "callout-note",
+            Self::Tip => "callout-tip",
+            Self::Important => "callout-important",
+            Self::Warning => "callout-warning",
+            Self::Caution => "callout-caution",
+        }
+    }
+}
+
+/// A replacement for a byte range of markdown, an empty range inserts
+pub type Edit = (Range<usize>, String);
+
+/// Apply the non-overlapping `edits` to `contents`
+pub fn splice(contents: &str, mut edits: Vec<Edit>) -> String {
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut spliced = String::with_capacity(contents.len());
+    let mut copied = 0;
+    for (range, replacement) in edits {
+        spliced.push_str(&contents[copied..range.start]);
+        spliced.push_str(&replacement);
+        copied = range.end;
+    }
+    spliced.push_str(&contents[copied..]);
+    spliced
+}
+
+/// Edits replacing top level paragraphs with the html `expand` returns for them
+pub fn expand_paragraphs(
+    contents: &str,
+    root: &Node,
+    mut expand: impl FnMut(&Node, &str) -> Option<String>,
+) -> Vec<Edit> {
+    root.children()
+        .into_iter()
+        .flatten()
+        .filter(|node| matches!(node, Node::Paragraph(_)))
+        .filter_map(|paragraph| {
+            let position = paragraph.position()?;
+            let range = position.start.offset..position.end.offset;
+            let html = expand(paragraph, &contents[range.clone()])?;
+            Some((range, html))
+        })
+        .collect()
+}
+
+/// A `<figure>` for a paragraph of only an image, captioned with its title
+pub fn figure(paragraph: &Node, link: bool) -> Option<String> {
+    let [Node::Image(image)] = paragraph.children()?.as_slice() else {
+        return None;
+    };
+    let caption = image.title.as_deref()?.trim();
+    if caption.is_empty() {
+        return None;
+    }
+    let src = escape(&image.url);
+    let mut img = format!("<img src=\"{src}\" alt=\"{}\" />", escape(&image.alt));
+    if link {
+        img = format!("<a class=\"figure-link\" href=\"{src}\">{img}</a>");
+    }
+    Some(format!(
+        "<figure>\n{img}\n<figcaption>{}</figcaption>\n</figure>",
+        escape(caption)
+    ))
+}
+
+/// A `<dl>` for a paragraph of `Term` lines followed by `: Definition` lines
+pub fn definition_list(paragraph: &str) -> Option<String> {
+    let lines: Vec<&str> = paragraph.lines().collect();
+    if lines.len() < 2 || lines.first()?.starts_with(": ") || !lines.last()?.starts_with(": ") {
+        return None;
+    }
+    let mut html = String::from("<dl class=\"definition-list\">\n");
+    for line in lines {
+        let (tag, text) = match line.strip_prefix(": ") {
+            Some(definition) => ("dd", definition),
+            None => ("dt", line),
+        };
+        // Blank lines keep the text parsed as markdown, post_html unwraps the paragraphs
+        _ = write!(html, "<{tag}>\n\n{}\n\n</{tag}>\n", text.trim());
+    }
+    html.push_str("</dl>");
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{definition_list, expand_paragraphs, splice};
+
+    fn parse(contents: &str) -> markdown::mdast::Node {
+        markdown::to_mdast(contents, &markdown::ParseOptions::gfm()).unwrap()
+    }
+
+    #[test]
+    fn splice_edits() {
+        let edits = vec![
+            (6..11, "there".to_owned()),
+            (0..0, "> ".to_owned()),
+            (11..12, String::new()),
+        ];
+        assert_eq!(splice("hello world!", edits), "> hello there");
+        assert_eq!(splice("unchanged", Vec::new()), "unchanged");
+    }
+
+    #[test]
+    fn multiple_definitions() {
+        let html = definition_list("Term\n: One\n: Two").unwrap();
+        assert_eq!(
+            html,
+            "<dl class=\"definition-list\">\n<dt>\n\nTerm\n\n</dt>\n<dd>\n\nOne\n\n</dd>\n\
+             <dd>\n\nTwo\n\n</dd>\n</dl>"
+        );
+    }
+
+    #[test]
+    fn term_without_definition() {
+        let html = definition_list("A\n: a\nB\nC\n: c").unwrap();
+        let tags: Vec<_> = html.lines().filter(|l| l.starts_with("<d")).collect();
+        assert_eq!(
+            tags,
+            [
+                "<dl class=\"definition-list\">",
+                "<dt>",
+                "<dd>",
+                "<dt>",
+                "<dt>",
+                "<dd>"
+            ]
+        );
+        assert_eq!(definition_list("Term\n: Definition\nTrailing term"), None);
+        assert_eq!(definition_list(": No term"), None);
+        assert_eq!(definition_list("No definition"), None);
+    }
+
+    #[test]
+    fn definitions_in_list_items() {
+        let contents = "- Term\n  : Definition\n\nTop\n: Level\n";
+        let edits = expand_paragraphs(contents, &parse(contents), |_, markdown| {
+            definition_list(markdown)
+        });
+        // Only top level paragraphs are expanded, the list item is left as written
+        assert_eq!(edits.len(), 1);
+        assert_eq!(&contents[edits[0].0.clone()], "Top\n: Level");
+    }
+}
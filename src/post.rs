@@ -1,10 +1,14 @@
-use std::sync::LazyLock;
+use std::collections::HashMap;
 
 use anyhow::{Context as _, Result};
-use markdown::mdast::{Heading, Image, Node, Text};
+use markdown::mdast::{Blockquote, Heading, Image, InlineCode, Node, Paragraph, Text};
 use serde::Serialize;
 
-use crate::config::PostConfig;
+use crate::{
+    config::{MarkdownConfig, PostConfig},
+    markdown_ext::{Callout, Edit},
+    toc::TocEntry,
+};
 
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct PostDetails {
@@ -15,26 +19,63 @@ pub struct PostDetails {
     pub tags: Vec<String>,
     pub description: String,
     pub headings: Vec<PostHeading>,
+    pub word_count: usize,
+    /// Estimated reading time in whole minutes
+    pub reading_time: usize,
     pub contents: String,
+    /// Shortcode outputs, restored in place of their placeholders once compiled
+    #[serde(skip)]
+    pub shortcodes: Vec<String>,
+}
+
+const WORDS_PER_MINUTE: usize = 200;
+/// The title, date and tags the markdown of a post starts with
+const FRONT_MATTER_NODES: usize = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PostTag {
+    pub name: String,
+    pub slug: String,
+}
+
+impl PostTag {
+    /// Tag slugs are normalised the same way as heading ids
+    pub fn new(name: &str) -> Self {
+        let name = name.trim();
+        Self {
+            name: name.to_owned(),
+            slug: kebab(name),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct PostHeading {
-    label: String,
-    kebab_label: String,
-    depth: u8,
+    pub label: String,
+    pub kebab_label: String,
+    pub depth: u8,
 }
 
 impl PostDetails {
-    pub fn extract(post_config: &PostConfig, contents: String) -> Result<Self> {
-        let node = markdown::to_mdast(&contents, &markdown::ParseOptions::default())
-            .expect("parse markdown");
+    pub fn extract(
+        post_config: &PostConfig,
+        config: &MarkdownConfig,
+        contents: String,
+    ) -> Result<Self> {
+        let node = markdown::to_mdast(&contents, &config.parse_options()).expect("parse markdown");
         let slug = post_config.slug.clone();
         let title = extract_title(&node).context("extract title")?;
         let image = extract_image(&node).context("extract image")?;
         let date = extract_date(&node).context("extract date")?;
         let tags = extract_tags(&node).context("extract tags")?;
         let headings = extract_headings(&node);
+        let word_count = node
+            .children()
+            .into_iter()
+            .flatten()
+            .skip(FRONT_MATTER_NODES)
+            .map(|node| extract_plain_text(node).split_whitespace().count())
+            .sum();
         Ok(Self {
             slug,
             title,
@@ -44,16 +85,67 @@ impl PostDetails {
             // TODO: Extract description
             description: String::default(),
             headings,
+            word_count,
+            reading_time: word_count.div_ceil(WORDS_PER_MINUTE).max(1),
             contents,
+            shortcodes: Vec::new(),
         })
     }
 
-    pub fn html_contents(&self) -> String {
-        let md_contents = find_md_code_blocks(&self.contents);
+    pub fn tag_links(&self) -> Vec<PostTag> {
+        self.tags.iter().map(|tag| PostTag::new(tag)).collect()
+    }
+
+    /// The readable text of the post, excluding code blocks, images and raw html
+    pub fn plain_text(&self, config: &MarkdownConfig) -> String {
+        let node =
+            markdown::to_mdast(&self.contents, &config.parse_options()).expect("parse markdown");
+        extract_plain_text(&node)
+    }
+
+    pub fn month_year(&self) -> Option<(u16, u8)> {
+        parse_month_year(&self.date)
+    }
+
+    /// The post date as `YYYY-MM`
+    pub fn w3c_date(&self) -> Option<String> {
+        let (year, month) = self.month_year()?;
+        Some(format!("{year:04}-{month:02}"))
+    }
+
+    pub fn html_contents(&self, toc: &[TocEntry], config: &MarkdownConfig) -> Result<String> {
+        let parse = config.parse_options();
+        let node =
+            markdown::to_mdast(&self.contents, &parse).map_err(|err| MarkdownToHtmlError {
+                msg: err.to_string(),
+            })?;
+        let (mut edits, callouts) = if config.callouts {
+            callout_markers(&self.contents, &node)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        edits.extend(heading_markers(&self.contents, &node));
+        edits.extend(crate::markdown_ext::expand_paragraphs(
+            &self.contents,
+            &node,
+            |paragraph, src| {
+                if config.figures
+                    && let Some(figure) =
+                        crate::markdown_ext::figure(paragraph, config.link_figures)
+                {
+                    return Some(figure);
+                }
+                config
+                    .definition_lists
+                    .then(|| crate::markdown_ext::definition_list(src))
+                    .flatten()
+            },
+        ));
+        let contents = crate::markdown_ext::splice(&self.contents, edits);
         let html = markdown::to_html_with_options(
-            &md_contents,
+            &contents,
             &markdown::Options {
-                parse: markdown::ParseOptions::gfm(),
+                parse,
                 compile: markdown::CompileOptions {
                     allow_dangerous_html: true,
                     ..markdown::CompileOptions::gfm()
@@ -62,9 +154,10 @@ impl PostDetails {
         )
         .map_err(|err| MarkdownToHtmlError {
             msg: err.to_string(),
-        })
-        .expect("markdown to html");
-        add_heading_ids(&html)
+        })?;
+        let html = crate::shortcode::restore(&html, &self.shortcodes);
+        let html = crate::rewrite::post_html(&html, &self.headings, &callouts)?;
+        Ok(crate::toc::replace_marker(&html, toc))
     }
 }
 
@@ -132,6 +225,16 @@ fn extract_text(node: &Node) -> Option<String> {
     })
 }
 
+fn extract_plain_text(node: &Node) -> String {
+    filter_map_ast(node, |node| match node {
+        Node::Text(Text { value, .. }) | Node::InlineCode(InlineCode { value, .. }) => {
+            Some(value.clone())
+        }
+        _ => None,
+    })
+    .join(" ")
+}
+
 fn extract_title(node: &Node) -> Option<String> {
     find_map_ast(node, |node| {
         if let Node::Heading(Heading { depth: 1, .. }) = node {
@@ -142,33 +245,48 @@ fn extract_title(node: &Node) -> Option<String> {
     })
 }
 
+/// The first image, without the `#preset=` fragment that only applies within the post
 fn extract_image(node: &Node) -> Option<String> {
     find_map_ast(node, |node| {
         if let Node::Image(Image { url, .. }) = node {
-            Some(url.to_owned())
+            Some(
+                url.split_once("#preset=")
+                    .map_or(url.as_str(), |(url, _)| url)
+                    .to_owned(),
+            )
         } else {
             None
         }
     })
 }
 
+const MONTHS: &[&str] = &[
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+/// Parse a date written like `January 2026` into `(year, month)` where month is 1 based
+fn parse_month_year(date: &str) -> Option<(u16, u8)> {
+    let date = date.to_lowercase();
+    let words = || date.split(|c: char| !c.is_ascii_alphanumeric());
+    let month = words().find_map(|word| MONTHS.iter().position(|m| *m == word))?;
+    let year = words().find_map(|word| word.parse::<u16>().ok().filter(|y| *y >= 1000))?;
+    Some((year, u8::try_from(month + 1).ok()?))
+}
+
 fn extract_date(node: &Node) -> Option<String> {
     find_map_ast(node, |node| {
         if let Node::Text(Text { value, .. }) = node {
-            const MONTHS: &[&str] = &[
-                "january",
-                "february",
-                "march",
-                "april",
-                "may",
-                "june",
-                "july",
-                "august",
-                "september",
-                "october",
-                "november",
-                "december",
-            ];
             if MONTHS.iter().any(|m| value.to_lowercase().contains(m)) {
                 Some(value.to_owned())
             } else {
@@ -184,59 +302,144 @@ fn extract_tags(node: &Node) -> Option<Vec<String>> {
     Some(
         extract_text(node.children()?.get(2)?)?
             .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
             .map(std::borrow::ToOwned::to_owned)
             .collect(),
     )
 }
 
 fn extract_headings(node: &Node) -> Vec<PostHeading> {
+    let mut slugger = Slugger::default();
     filter_map_ast(node, |node| {
         if let Node::Heading(Heading { depth, .. }) = node {
-            let label = extract_text(node)?;
-            Some(PostHeading {
-                kebab_label: kebab(&label),
-                label,
-                depth: *depth,
-            })
+            Some((extract_inline_text(node), *depth))
         } else {
             None
         }
     })
+    .into_iter()
+    .map(|(label, depth)| PostHeading {
+        kebab_label: slugger.slug(&label),
+        label,
+        depth,
+    })
+    .collect()
 }
 
-fn add_heading_ids(contents: &str) -> String {
-    static HEADER_PATTERN: LazyLock<regex::Regex> =
-        LazyLock::new(|| regex::Regex::new("<h([1-6])>([^<]+)</h").expect("compile regex"));
-    HEADER_PATTERN
-        .replace_all(contents, |cap: &regex::Captures<'_>| {
-            let rank = cap.get(1).expect("regex capture").as_str();
-            let inner = cap.get(2).expect("regex capture").as_str();
-            let kebab_inner = kebab(inner);
-            format!("<h{rank} id=\"{kebab_inner}\">{inner}</h")
-        })
-        .to_string()
+/// Edits inserting a [`crate::rewrite::heading_marker`] after the text of each heading
+fn heading_markers(contents: &str, node: &Node) -> Vec<Edit> {
+    filter_map_ast(node, |node| match node {
+        Node::Heading(Heading { children, .. }) => Some(
+            children
+                .last()
+                .and_then(Node::position)
+                .map(|p| p.end.offset),
+        ),
+        _ => None,
+    })
+    .into_iter()
+    .enumerate()
+    .filter_map(|(index, end)| {
+        let end = end?;
+        // A trailing backslash would escape the marker
+        let space = if contents[..end].ends_with('\\') {
+            " "
+        } else {
+            ""
+        };
+        Some((
+            end..end,
+            format!("{space}{}", crate::rewrite::heading_marker(index)),
+        ))
+    })
+    .collect()
+}
+
+/// Edits replacing the marker of each callout with a [`crate::rewrite::callout_marker`]
+fn callout_markers(contents: &str, node: &Node) -> (Vec<Edit>, Vec<Callout>) {
+    filter_map_ast(node, |node| {
+        let Node::Blockquote(Blockquote { children, .. }) = node else {
+            return None;
+        };
+        let Some(Node::Paragraph(Paragraph {
+            children: inline, ..
+        })) = children.first()
+        else {
+            return None;
+        };
+        let text @ Node::Text(Text { value, .. }) = inline.first()? else {
+            return None;
+        };
+        let (callout, _) = Callout::strip_marker(value)?;
+        let start = text.position()?.start.offset;
+        let end = start + contents[start..].find(']')? + 1;
+        Some((start..end, callout))
+    })
+    .into_iter()
+    .enumerate()
+    .map(|(index, (range, callout))| ((range, crate::rewrite::callout_marker(index)), callout))
+    .unzip()
+}
+
+fn extract_inline_text(node: &Node) -> String {
+    filter_map_ast(node, |node| match node {
+        Node::Text(Text { value, .. }) | Node::InlineCode(InlineCode { value, .. }) => {
+            Some(value.clone())
+        }
+        _ => None,
+    })
+    .concat()
 }
 
 fn kebab(s: &str) -> String {
-    s.to_lowercase().replace(' ', "-")
-}
-
-fn find_md_code_blocks(contents: &str) -> String {
-    static CODE_BLOCK_PATTERN: LazyLock<regex::Regex> = LazyLock::new(|| {
-        regex::Regex::new("(?s)```([a-zA-Z0-9_-]*)?(.*?)```").expect("compile regex")
-    });
-    CODE_BLOCK_PATTERN
-        .replace_all(contents, |cap: &regex::Captures<'_>| {
-            let language = cap.get(1).expect("captures").as_str();
-            let Some(src) = cap.get(2) else {
-                return cap.get_match().as_str().to_string();
-            };
-            crate::highlight::src_to_highlight_html(
-                language,
-                src.as_str().trim_matches(['\n', '\r']),
-            )
-            .expect("highlight code block")
-            .unwrap_or_else(|| cap.get_match().as_str().to_string())
-        })
-        .to_string()
+    s.to_lowercase()
+        .chars()
+        .filter(|&c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
+
+/// Suffixes repeated slugs with `-1`, `-2`, ... like GitHub
+#[derive(Default)]
+struct Slugger {
+    occurrences: HashMap<String, usize>,
+}
+
+impl Slugger {
+    fn slug(&mut self, s: &str) -> String {
+        let original = kebab(s);
+        let mut slug = original.clone();
+        while self.occurrences.contains_key(&slug) {
+            let count = self.occurrences.entry(original.clone()).or_default();
+            *count += 1;
+            slug = format!("{original}-{count}");
+        }
+        self.occurrences.insert(slug.clone(), 0);
+        slug
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Slugger, kebab};
+
+    #[test]
+    fn kebab_labels() {
+        assert_eq!(kebab("Hello, World!"), "hello-world");
+        assert_eq!(kebab("C++ & Rust"), "c--rust");
+        assert_eq!(kebab("snake_case and-dashes"), "snake_case-and-dashes");
+        assert_eq!(kebab("Über Café 2"), "über-café-2");
+    }
+
+    #[test]
+    fn unique_slugs() {
+        let mut slugger = Slugger::default();
+        let slugs: Vec<_> = ["Foo", "Foo", "foo!", "Foo 1", "Foo"]
+            .into_iter()
+            .map(|s| slugger.slug(s))
+            .collect();
+        // "Foo 1" would collide with the first repeat so is itself suffixed
+        assert_eq!(slugs, ["foo", "foo-1", "foo-2", "foo-1-1", "foo-3"]);
+    }
 }
@@ -0,0 +1,112 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use crate::post::PostDetails;
+
+/// The posts most related to `post` by shared tags and then by TF-IDF similarity of their contents
+pub fn related_posts(post: &PostDetails, posts: &[PostDetails], count: usize) -> Vec<PostDetails> {
+    let others: Vec<&PostDetails> = posts.iter().filter(|p| p.slug != post.slug).collect();
+    let target_terms = term_frequencies(&post.contents);
+    let other_terms: Vec<HashMap<String, f64>> = others
+        .iter()
+        .map(|p| term_frequencies(&p.contents))
+        .collect();
+    let idf = inverse_document_frequencies(other_terms.iter().chain([&target_terms]));
+    let target_vector = tf_idf(&target_terms, &idf);
+    let target_tags = tag_slugs(post);
+
+    let mut scored: Vec<(f64, &PostDetails)> = others
+        .into_iter()
+        .zip(&other_terms)
+        .map(|(other, terms)| {
+            let shared_tags = tag_slugs(other).intersection(&target_tags).count();
+            let similarity = cosine_similarity(&target_vector, &tf_idf(terms, &idf));
+            (shared_tags as f64 + similarity, other)
+        })
+        .filter(|(score, _)| *score > 0.0)
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    scored
+        .into_iter()
+        .take(count)
+        .map(|(_, p)| p.clone())
+        .collect()
+}
+
+/// The posts published before and after `post`, with `posts` newest first to break ties
+pub fn chronological_neighbours<'a>(
+    post: &PostDetails,
+    posts: &'a [PostDetails],
+) -> (Option<&'a PostDetails>, Option<&'a PostDetails>) {
+    let mut ordered: Vec<(usize, &PostDetails)> = posts.iter().enumerate().collect();
+    ordered.sort_by_key(|&(i, p)| (p.month_year(), Reverse(i)));
+    let Some(index) = ordered.iter().position(|(_, p)| p.slug == post.slug) else {
+        return (None, None);
+    };
+    let previous = index
+        .checked_sub(1)
+        .and_then(|i| ordered.get(i))
+        .map(|&(_, p)| p);
+    let next = ordered.get(index + 1).map(|&(_, p)| p);
+    (previous, next)
+}
+
+fn tag_slugs(post: &PostDetails) -> HashSet<String> {
+    post.tag_links().into_iter().map(|tag| tag.slug).collect()
+}
+
+fn term_frequencies(text: &str) -> HashMap<String, f64> {
+    let mut terms = HashMap::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 2)
+    {
+        *terms.entry(word.to_lowercase()).or_default() += 1.0;
+    }
+    terms
+}
+
+fn inverse_document_frequencies<'a>(
+    documents: impl Iterator<Item = &'a HashMap<String, f64>>,
+) -> HashMap<String, f64> {
+    let mut document_count = 0_u32;
+    let mut frequencies = HashMap::<String, f64>::new();
+    for terms in documents {
+        document_count += 1;
+        for term in terms.keys() {
+            *frequencies.entry(term.clone()).or_default() += 1.0;
+        }
+    }
+    for frequency in frequencies.values_mut() {
+        *frequency = (f64::from(document_count) / *frequency).ln();
+    }
+    frequencies
+}
+
+fn tf_idf(terms: &HashMap<String, f64>, idf: &HashMap<String, f64>) -> HashMap<String, f64> {
+    terms
+        .iter()
+        .map(|(term, tf)| {
+            (
+                term.clone(),
+                tf * idf.get(term).copied().unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+fn cosine_similarity(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(term, x)| Some(x * b.get(term)?))
+        .sum();
+    let norm = |v: &HashMap<String, f64>| v.values().map(|x| x * x).sum::<f64>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator > 0.0 {
+        dot / denominator
+    } else {
+        0.0
+    }
+}
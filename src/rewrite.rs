@@ -0,0 +1,249 @@
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+use anyhow::Result;
+use lol_html::{
+    ElementContentHandlers, RewriteStrSettings, Selector, comments, element, end_tag,
+    html_content::{ContentType, Element},
+    text,
+};
+
+use crate::{markdown_ext::Callout, post::PostHeading};
+
+type Handler<'h> = (Cow<'static, Selector>, ElementContentHandlers<'h>);
+
+const HEADINGS: &str = "h1, h2, h3, h4, h5, h6";
+const HEADING_MARKER: &str = "heading";
+const CALLOUT_MARKER: &str = "callout";
+
+/// Give headings anchors, turn blockquotes into callouts, unwrap definition list paragraphs and
+/// highlight code, matching elements to `headings` and `callouts` by their [`marker`]
+pub fn post_html(html: &str, headings: &[PostHeading], callouts: &[Callout]) -> Result<String> {
+    let heading_markers = find_markers(html, HEADINGS, HEADING_MARKER)?;
+    let callout_markers = find_markers(html, "blockquote", CALLOUT_MARKER)?;
+    let mut handlers = heading_handlers(headings, heading_markers);
+    handlers.extend(callout_handlers(callouts, callout_markers));
+    handlers.push(element!(
+        "dl.definition-list > dt > p, dl.definition-list > dd > p",
+        |el| {
+            el.remove_and_keep_content();
+            Ok(())
+        }
+    ));
+    handlers.extend(code_block_handlers());
+    Ok(lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::new()
+        },
+    )?)
+}
+
+/// Marks the heading rendered for `headings[index]` of [`post_html`]
+pub fn heading_marker(index: usize) -> String {
+    marker(HEADING_MARKER, index)
+}
+
+/// Replaces the `[!NOTE]` style marker of `callouts[index]` of [`post_html`]
+pub fn callout_marker(index: usize) -> String {
+    marker(CALLOUT_MARKER, index)
+}
+
+fn marker(kind: &str, index: usize) -> String {
+    format!("<!-- {kind} {index} -->")
+}
+
+fn parse_marker(comment: &str, kind: &str) -> Option<usize> {
+    comment
+        .trim()
+        .strip_prefix(kind)?
+        .strip_prefix(' ')?
+        .parse()
+        .ok()
+}
+
+/// The index of the `kind` marker inside each element matching `selector`, in document order
+fn find_markers(html: &str, selector: &str, kind: &str) -> Result<Vec<Option<usize>>> {
+    let found = RefCell::new(Vec::new());
+    // The elements the parser is inside of, innermost last
+    let open = Rc::new(RefCell::new(Vec::new()));
+    let comment_open = Rc::clone(&open);
+    lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!(selector, |el| {
+                    let mut found = found.borrow_mut();
+                    open.borrow_mut().push(found.len());
+                    found.push(None);
+                    let open = Rc::clone(&open);
+                    el.on_end_tag(end_tag!(move |_| {
+                        open.borrow_mut().pop();
+                        Ok(())
+                    }))
+                }),
+                comments!(selector, |comment| {
+                    if let Some(index) = parse_marker(&comment.text(), kind)
+                        && let Some(&el) = comment_open.borrow().last()
+                    {
+                        found.borrow_mut()[el] = Some(index);
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(found.into_inner())
+}
+
+fn remove_markers<'h>(selector: &str, kind: &'static str) -> Handler<'h> {
+    comments!(selector, move |comment| {
+        if parse_marker(&comment.text(), kind).is_some() {
+            comment.remove();
+        }
+        Ok(())
+    })
+}
+
+fn heading_handlers(headings: &[PostHeading], markers: Vec<Option<usize>>) -> Vec<Handler<'_>> {
+    let mut markers = markers.into_iter();
+    vec![
+        element!(HEADINGS, move |el| {
+            let Some(heading) = markers
+                .next()
+                .flatten()
+                .and_then(|index| headings.get(index))
+            else {
+                return Ok(());
+            };
+            let id = &heading.kebab_label;
+            el.set_attribute("id", id)?;
+            if heading.depth > 1 {
+                el.prepend(
+                    &format!(
+                        "<a class=\"heading-anchor\" href=\"#{id}\" aria-label=\"Link to this section\">#</a>"
+                    ),
+                    ContentType::Html,
+                );
+            }
+            Ok(())
+        }),
+        remove_markers(HEADINGS, HEADING_MARKER),
+    ]
+}
+
+fn callout_handlers(callouts: &[Callout], markers: Vec<Option<usize>>) -> Vec<Handler<'_>> {
+    let mut markers = markers.into_iter();
+    vec![
+        element!("blockquote", move |el| {
+            let Some(callout) = markers
+                .next()
+                .flatten()
+                .and_then(|index| callouts.get(index))
+            else {
+                return Ok(());
+            };
+            el.set_tag_name("aside")?;
+            el.set_attribute("class", &format!("callout {}", callout.class()))?;
+            el.set_attribute("role", "note")?;
+            el.prepend(
+                &format!("<p class=\"callout-title\">{}</p>", callout.title()),
+                ContentType::Html,
+            );
+            Ok(())
+        }),
+        remove_markers("blockquote", CALLOUT_MARKER),
+    ]
+}
+
+fn code_block_handlers<'h>() -> Vec<Handler<'h>> {
+    // The source of the code block being highlighted, while inside one
+    let code_block = Rc::new(RefCell::new(None::<String>));
+    let text_code_block = Rc::clone(&code_block);
+    vec![
+        element!("pre > code[class]", move |el| {
+            let class = attribute(el, "class").unwrap_or_default();
+            let Some(language) = class
+                .split_whitespace()
+                .find_map(|c| c.strip_prefix("language-"))
+                .filter(|language| crate::highlight::supports(language))
+                .map(str::to_owned)
+            else {
+                return Ok(());
+            };
+            el.set_attribute("class", "nohighlight tree-sitter")?;
+            *code_block.borrow_mut() = Some(String::new());
+            let code_block = Rc::clone(&code_block);
+            el.on_end_tag(end_tag!(move |end| {
+                let src = code_block.borrow_mut().take().unwrap_or_default();
+                let highlighted = crate::highlight::src_to_highlight_html(
+                    &language,
+                    decode_entities(&src).trim_matches(['\n', '\r']),
+                )?;
+                end.before(&highlighted.unwrap_or(src), ContentType::Html);
+                Ok(())
+            }))
+        }),
+        text!("pre > code", move |chunk| {
+            if let Some(src) = text_code_block.borrow_mut().as_mut() {
+                src.push_str(chunk.as_str());
+                chunk.remove();
+            }
+            Ok(())
+        }),
+    ]
+}
+
+/// The attribute with character references decoded, such as the `&#x2F;` tera escapes to
+pub fn attribute(el: &Element<'_, '_>, name: &str) -> Option<String> {
+    el.get_attribute(name).map(|value| decode_entities(&value))
+}
+
+/// Escape text for html or xml, in content or a quoted attribute
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn decode_entities(raw: &str) -> String {
+    let mut decoded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some((reference, after)) = rest[1..].split_once(';')
+            && let Some(c) = decode_reference(reference)
+        {
+            decoded.push(c);
+            rest = after;
+        } else {
+            decoded.push('&');
+            rest = &rest[1..];
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_reference(reference: &str) -> Option<char> {
+    match reference {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let number = reference.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
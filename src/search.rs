@@ -0,0 +1,70 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use crate::{config::MarkdownConfig, post::PostDetails};
+
+const TITLE_WEIGHT: u32 = 10;
+const TAG_WEIGHT: u32 = 5;
+const HEADING_WEIGHT: u32 = 3;
+const BODY_WEIGHT: u32 = 1;
+
+/// An inverted index from terms to the posts that contain them, searched by `search.js`
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchIndex {
+    posts: Vec<SearchPost>,
+    /// Each term maps to `[post index, weight]` pairs
+    terms: BTreeMap<String, Vec<(usize, u32)>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SearchPost {
+    slug: String,
+    title: String,
+    date: String,
+    tags: Vec<String>,
+}
+
+impl SearchIndex {
+    pub fn build(posts: &[PostDetails], config: &MarkdownConfig) -> Self {
+        let mut terms = BTreeMap::<String, Vec<(usize, u32)>>::new();
+        for (index, post) in posts.iter().enumerate() {
+            let mut weights = HashMap::<String, u32>::new();
+            let mut add = |text: &str, weight: u32| {
+                for term in tokenize(text) {
+                    *weights.entry(term).or_default() += weight;
+                }
+            };
+            add(&post.title, TITLE_WEIGHT);
+            for tag in &post.tags {
+                add(tag, TAG_WEIGHT);
+            }
+            for heading in post.headings.iter().filter(|h| h.depth > 1) {
+                add(&heading.label, HEADING_WEIGHT);
+            }
+            add(&post.plain_text(config), BODY_WEIGHT);
+            for (term, weight) in weights {
+                terms.entry(term).or_default().push((index, weight));
+            }
+        }
+        Self {
+            posts: posts
+                .iter()
+                .map(|post| SearchPost {
+                    slug: post.slug.clone(),
+                    title: post.title.clone(),
+                    date: post.date.clone(),
+                    tags: post.tags.clone(),
+                })
+                .collect(),
+            terms,
+        }
+    }
+}
+
+/// Split text into lowercase terms, this must match `tokenize` in `search.js`
+fn tokenize(text: &str) -> impl Iterator<Item = String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| term.chars().count() > 1)
+        .map(str::to_lowercase)
+}
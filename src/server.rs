@@ -27,7 +27,7 @@ pub fn serve(config: ServerConfig) -> Result<()> {
     let cache = jobber::Cache::new(config.build_config.build_cache_size);
     let watch_dir = config.build_config.root_dir.clone();
     let serve_dir = config.build_config.output_dir.clone();
-    let site = Site::new(config.build_config, config.hot_reload);
+    let site = Site::new(config.build_config, config.hot_reload, true);
 
     // Initial build
     let h = match site.build_site_with_cache(&cache) {
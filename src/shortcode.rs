@@ -0,0 +1,345 @@
+use std::ops::Range;
+
+use anyhow::{Context as _, Result, bail};
+use markdown::mdast::Node;
+
+/// A `{{ name(key=value) }}` or `{% name(key=value) %}body{% end %}` call in a post
+#[derive(Debug, Clone)]
+pub struct Shortcode {
+    pub name: String,
+    pub args: serde_json::Map<String, serde_json::Value>,
+    /// The raw text between a `{% %}` shortcode and its `{% end %}`
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expanded {
+    pub contents: String,
+    pub outputs: Vec<String>,
+}
+
+fn placeholder(index: usize) -> String {
+    format!("<!-- shortcode {index} -->")
+}
+
+/// Replace shortcodes outside of code with placeholders for their output from `render`
+pub fn expand(
+    contents: &str,
+    parse: &markdown::ParseOptions,
+    mut render: impl FnMut(&Shortcode) -> Result<String>,
+) -> Result<Expanded> {
+    let literals = literal_ranges(contents, parse)?;
+    let mut outputs = Vec::new();
+    let mut expanded = String::with_capacity(contents.len());
+    let mut copied = 0;
+    let mut pos = 0;
+    while let Some(offset) = contents[pos..].find('{') {
+        let start = pos + offset;
+        if let Some(literal) = literals.iter().find(|range| range.contains(&start)) {
+            pos = literal.end;
+            continue;
+        }
+        let line = contents[..start].matches('\n').count() + 1;
+        let Some((shortcode, end)) =
+            parse_call(contents, start).with_context(|| format!("shortcode on line {line}"))?
+        else {
+            pos = start + 1;
+            continue;
+        };
+        let output = render(&shortcode)
+            .with_context(|| format!("render shortcode {} on line {line}", shortcode.name))?;
+        expanded.push_str(&contents[copied..start]);
+        expanded.push_str(&placeholder(outputs.len()));
+        outputs.push(output);
+        copied = end;
+        pos = end;
+    }
+    expanded.push_str(&contents[copied..]);
+    Ok(Expanded {
+        contents: expanded,
+        outputs,
+    })
+}
+
+pub fn restore(html: &str, outputs: &[String]) -> String {
+    outputs
+        .iter()
+        .enumerate()
+        .fold(html.to_owned(), |html, (index, output)| {
+            html.replacen(&placeholder(index), output, 1)
+        })
+}
+
+/// Byte ranges of code, where shortcodes are left as written
+fn literal_ranges(contents: &str, parse: &markdown::ParseOptions) -> Result<Vec<Range<usize>>> {
+    fn helper(node: &Node, ranges: &mut Vec<Range<usize>>) {
+        match node {
+            Node::Code(_) | Node::InlineCode(_) => {
+                if let Some(position) = node.position() {
+                    ranges.push(position.start.offset..position.end.offset);
+                }
+            }
+            _ => {
+                for child in node.children().into_iter().flatten() {
+                    helper(child, ranges);
+                }
+            }
+        }
+    }
+    let node = markdown::to_mdast(contents, parse).map_err(|err| anyhow::anyhow!("{err}"))?;
+    let mut ranges = Vec::new();
+    helper(&node, &mut ranges);
+    Ok(ranges)
+}
+
+fn parse_call(contents: &str, start: usize) -> Result<Option<(Shortcode, usize)>> {
+    let rest = &contents[start..];
+    let close = if rest.starts_with("{{") {
+        "}}"
+    } else if rest.starts_with("{%") {
+        "%}"
+    } else {
+        return Ok(None);
+    };
+    let mut cursor = Cursor {
+        contents,
+        pos: start + 2,
+    };
+    cursor.skip_whitespace();
+    let name = cursor.identifier();
+    if name.is_empty() || !cursor.eat("(") {
+        return Ok(None);
+    }
+    let args = cursor.args()?;
+    cursor.skip_whitespace();
+    if !cursor.eat(close) {
+        bail!("expected {close} after the arguments of {name}");
+    }
+    let (body, end) = if close == "%}" {
+        let (body, end) = find_end(contents, cursor.pos)
+            .with_context(|| format!("missing {{% end %}} for {name}"))?;
+        (Some(body.to_owned()), end)
+    } else {
+        (None, cursor.pos)
+    };
+    Ok(Some((Shortcode { name, args, body }, end)))
+}
+
+fn find_end(contents: &str, from: usize) -> Option<(&str, usize)> {
+    let mut pos = from;
+    loop {
+        let open = pos + contents[pos..].find("{%")?;
+        pos = open + 2;
+        if let Some(rest) = contents[pos..].trim_start().strip_prefix("end")
+            && let Some(rest) = rest.trim_start().strip_prefix("%}")
+        {
+            return Some((&contents[from..open], contents.len() - rest.len()));
+        }
+    }
+}
+
+struct Cursor<'a> {
+    contents: &'a str,
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn rest(&self) -> &str {
+        &self.contents[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.contents.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &self.contents[start..self.pos]
+    }
+
+    fn identifier(&mut self) -> String {
+        self.take_while(|c| c.is_ascii_alphanumeric() || c == '_')
+            .to_owned()
+    }
+
+    fn args(&mut self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let mut args = serde_json::Map::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(")") {
+                return Ok(args);
+            }
+            let key = self.identifier();
+            if key.is_empty() {
+                bail!(
+                    "expected an argument name at {:?}",
+                    self.rest().lines().next()
+                );
+            }
+            self.skip_whitespace();
+            if !self.eat("=") {
+                bail!("expected = after argument {key}");
+            }
+            self.skip_whitespace();
+            let value = self.value().with_context(|| format!("argument {key}"))?;
+            args.insert(key, value);
+            self.skip_whitespace();
+            if !self.eat(",") && !self.rest().starts_with(')') {
+                bail!("expected , or ) after an argument");
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<serde_json::Value> {
+        if let Some(quote) = self
+            .rest()
+            .chars()
+            .next()
+            .filter(|c| matches!(c, '"' | '\''))
+        {
+            self.pos += 1;
+            let mut value = String::new();
+            let mut chars = self.rest().char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        let (_, escaped) = chars.next().context("unterminated string")?;
+                        value.push(if escaped == 'n' { '\n' } else { escaped });
+                    }
+                    c if c == quote => {
+                        self.pos += i + 1;
+                        return Ok(serde_json::Value::String(value));
+                    }
+                    c => value.push(c),
+                }
+            }
+            bail!("unterminated string");
+        }
+        let literal =
+            self.take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.'));
+        Ok(match literal {
+            "true" => serde_json::Value::Bool(true),
+            "false" => serde_json::Value::Bool(false),
+            _ => {
+                if let Ok(integer) = literal.parse::<i64>() {
+                    integer.into()
+                } else if let Ok(float) = literal.parse::<f64>()
+                    && let Some(number) = serde_json::Number::from_f64(float)
+                {
+                    number.into()
+                } else {
+                    bail!("expected a string, number or boolean but found {literal:?}")
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{Shortcode, expand, restore};
+
+    fn parse() -> markdown::ParseOptions {
+        markdown::ParseOptions::gfm()
+    }
+
+    #[expect(clippy::unnecessary_wraps)]
+    fn render(shortcode: &Shortcode) -> Result<String> {
+        Ok(format!(
+            "[{} {} {:?}]",
+            shortcode.name,
+            serde_json::Value::Object(shortcode.args.clone()),
+            shortcode.body
+        ))
+    }
+
+    #[test]
+    fn expands_to_placeholders() {
+        let expanded = expand(
+            "a {{ video(id=\"x\", start=3, autoplay=true) }} b\n{% note() %}body{% end %}",
+            &parse(),
+            render,
+        )
+        .unwrap();
+        assert_eq!(
+            expanded.contents,
+            "a <!-- shortcode 0 --> b\n<!-- shortcode 1 -->"
+        );
+        assert_eq!(
+            expanded.outputs,
+            [
+                r#"[video {"autoplay":true,"id":"x","start":3} None]"#,
+                r#"[note {} Some("body")]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn body_containing_delimiters() {
+        let expanded = expand("{% note() %}a {%} b{% end %}", &parse(), render).unwrap();
+        assert_eq!(expanded.outputs, [r#"[note {} Some("a {%} b")]"#]);
+    }
+
+    #[test]
+    fn skips_code() {
+        let contents = "`{{ a() }}`\n\n```\n{{ b() }}\n```";
+        let expanded = expand(contents, &parse(), render).unwrap();
+        assert_eq!(expanded.contents, contents);
+        assert!(expanded.outputs.is_empty());
+    }
+
+    #[test]
+    fn restores_output_unfiltered() {
+        let expanded = expand("{{ embed() }}\n\ntext", &parse(), |_| {
+            Ok("<iframe src=\"x\"></iframe>\n<pre>a\n\nb</pre>".to_owned())
+        })
+        .unwrap();
+        let html = markdown::to_html_with_options(
+            &expanded.contents,
+            &markdown::Options {
+                parse: parse(),
+                compile: markdown::CompileOptions {
+                    allow_dangerous_html: true,
+                    ..markdown::CompileOptions::gfm()
+                },
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            restore(&html, &expanded.outputs),
+            "<iframe src=\"x\"></iframe>\n<pre>a\n\nb</pre>\n<p>text</p>"
+        );
+    }
+
+    #[test]
+    fn errors_report_the_line() {
+        let err = expand("a\n\n{{ a(x=) }}", &parse(), render).unwrap_err();
+        assert_eq!(err.to_string(), "shortcode on line 3");
+        let err = expand("{% a() %}no end", &parse(), render).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "shortcode on line 1: missing {% end %} for a"
+        );
+    }
+
+    #[test]
+    fn not_shortcodes() {
+        let contents = "{ {{ {% %} {{ a }} {{ (b) }}";
+        let expanded = expand(contents, &parse(), render).unwrap();
+        assert_eq!(expanded.contents, contents);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io::Cursor, path::Path, str, sync::Arc};
+use std::{collections::BTreeMap, fmt::Write as _, io::Cursor, path::Path, str, sync::Arc};
 
 use anyhow::{Context as _, Result};
 use harper_core::{
@@ -8,13 +8,16 @@ use harper_core::{
     spell::{FstDictionary, MergedDictionary},
 };
 use jobber::{Cache, JobCtx, JobIdBuilder};
+use lol_html::html_content::ContentType;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{BuildConfig, PostConfig, SiteConfig},
-    img_conversion::{ImageConvertFormat, Quality},
-    post::PostDetails,
+    config::{BuildConfig, Date, ImageConfig, PostConfig, SiteConfig},
+    img_conversion::{ImageConvertFormat, ImageFit, ImagePreset, Placeholder},
+    post::{PostDetails, PostTag},
     progress::{DefaultSiteBuildProgress, NoSiteBuildProgress, SiteBuildProgress},
+    search::SearchIndex,
+    sitemap::SitemapUrl,
 };
 
 #[derive(Debug, Clone, Serialize)]
@@ -24,6 +27,50 @@ struct Info {
     featured: Vec<PostDetails>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct TagPage {
+    tag: PostTag,
+    posts: Vec<PostDetails>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Paginator {
+    current_page: usize,
+    total_pages: usize,
+    prev: Option<String>,
+    next: Option<String>,
+}
+
+impl Paginator {
+    fn page_url(base_url: &str, page: usize) -> String {
+        if page == 1 {
+            base_url.to_owned()
+        } else {
+            format!("{base_url}page/{page}/")
+        }
+    }
+}
+
+/// Values for the Open Graph and Twitter card `<meta>` tags of a post
+#[derive(Debug, Clone, Serialize)]
+struct OpenGraph {
+    title: String,
+    description: String,
+    url: String,
+    image: String,
+    image_width: u32,
+    image_height: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Picture {
+    sources: Vec<(ImageConvertFormat, String)>,
+    fallback: String,
+    placeholder: Placeholder,
+    width: u32,
+    height: u32,
+}
+
 #[derive(Deserialize)]
 pub struct AdditionalDictionary {
     pub words: Vec<AdditionalWord>,
@@ -38,13 +85,15 @@ pub struct AdditionalWord {
 pub struct Site {
     config: BuildConfig,
     include_hot_reload: bool,
+    include_drafts: bool,
 }
 
 impl Site {
-    pub fn new(config: BuildConfig, include_hot_reload: bool) -> Self {
+    pub fn new(config: BuildConfig, include_hot_reload: bool, include_drafts: bool) -> Self {
         Self {
             config,
             include_hot_reload,
+            include_drafts,
         }
     }
     pub fn build_site_with_cache(&self, cache: &Cache) -> Result<u64> {
@@ -71,6 +120,8 @@ impl Site {
     }
 
     fn build_site(&self, ctx: &mut JobCtx<'_>) -> Result<()> {
+        // Passed to the jobs that depend on it so they rerun once posts are published
+        let today = Date::today();
         self.download_third_party_asset(
             ctx,
             "https://cdnjs.cloudflare.com/ajax/libs/normalize/8.0.1/normalize.min.css",
@@ -111,7 +162,7 @@ impl Site {
             Path::new("assets/favicon.ico"),
             Path::new("favicon.ico"),
         )?;
-        self.copyfile(ctx, Path::new("assets/robots.txt"), Path::new("robots.txt"))?;
+        self.render_robots(ctx)?;
         self.copyfile(
             ctx,
             Path::new("assets/security.txt"),
@@ -122,14 +173,18 @@ impl Site {
             Path::new("assets/security.txt"),
             Path::new(".well-known/security.txt"),
         )?;
-        self.render_template_css(ctx, "style.css", Path::new("style.css"))?;
-        self.render_template_js(ctx, "theme.js", Path::new("theme.js"))?;
-        self.render_template_js(ctx, "navbar.js", Path::new("navbar.js"))?;
-        self.render_template_js(ctx, "hl_all.js", Path::new("hl_all.js"))?;
-        self.render_all_posts(ctx)?;
-        self.render_template_html(ctx, "index.html", Path::new("index.html"))?;
-        self.render_template_html(ctx, "404.html", Path::new("404.html"))?;
-        self.render_all_pages(ctx)?;
+        self.render_template_css(ctx, today, "style.css", Path::new("style.css"))?;
+        self.render_template_js(ctx, today, "theme.js", Path::new("theme.js"))?;
+        self.render_template_js(ctx, today, "navbar.js", Path::new("navbar.js"))?;
+        self.render_template_js(ctx, today, "hl_all.js", Path::new("hl_all.js"))?;
+        self.render_template_js(ctx, today, "search.js", Path::new("search.js"))?;
+        self.render_all_posts(ctx, today)?;
+        self.render_template_html(ctx, today, "index.html", Path::new("index.html"))?;
+        self.render_template_html(ctx, today, "404.html", Path::new("404.html"))?;
+        self.render_all_pages(ctx, today)?;
+        self.render_all_tags(ctx, today)?;
+        self.render_search_index(ctx, today)?;
+        self.render_sitemap(ctx, today)?;
         Ok(())
     }
 
@@ -143,13 +198,58 @@ impl Site {
         Ok(cfg)
     }
 
+    /// The configured posts merged with the markdown files found in the posts directory
     #[jobber::job]
-    fn post_markdown(&self, ctx: &mut JobCtx<'_>, post_config: &PostConfig) -> Result<String> {
+    fn post_configs(&self, ctx: &mut JobCtx<'_>) -> Result<Vec<PostConfig>> {
         let site_config = self.site_config_loader(ctx)?;
         let src = self.config.root_dir.join("posts");
-        site_config
-            .pages
-            .posts
+        ctx.depends_glob(&src.join("*.md").display().to_string())?;
+        let mut slugs = Vec::new();
+        for entry in std::fs::read_dir(&src).context(format!("read {src:?}"))? {
+            let path = entry?.path();
+            if path.is_file()
+                && path.extension().is_some_and(|ext| ext == "md")
+                && let Some(slug) = path.file_stem().and_then(|stem| stem.to_str())
+            {
+                slugs.push(slug.to_owned());
+            }
+        }
+        slugs.sort();
+
+        let mut post_configs = Vec::new();
+        for post_config in &site_config.pages.posts {
+            if slugs.contains(&post_config.slug) {
+                post_configs.push(post_config.clone());
+            } else {
+                log::warn!(
+                    "post {} is configured but has no markdown file in {src:?}",
+                    post_config.slug
+                );
+            }
+        }
+        for slug in slugs {
+            if post_configs.iter().any(|p| p.slug == slug) {
+                continue;
+            }
+            if !site_config.pages.discover_posts {
+                log::warn!("post {slug} has a markdown file but is not published");
+                continue;
+            }
+            let post_config = PostConfig::new(slug);
+            if let Err(err) = post_config.validate() {
+                log::warn!("skipping discovered post: {err}");
+                continue;
+            }
+            post_configs.push(post_config);
+        }
+        Ok(post_configs)
+    }
+
+    #[jobber::job]
+    fn post_markdown(&self, ctx: &mut JobCtx<'_>, post_config: &PostConfig) -> Result<String> {
+        let post_configs = self.post_configs(ctx)?;
+        let src = self.config.root_dir.join("posts");
+        post_configs
             .iter()
             .find(|&p| p.slug == post_config.slug)
             .context("could not find post")?;
@@ -162,17 +262,27 @@ impl Site {
     #[jobber::job]
     fn post_loader(&self, ctx: &mut JobCtx<'_>, post_config: &PostConfig) -> Result<PostDetails> {
         let contents = self.post_markdown(ctx, post_config)?;
-        let post = PostDetails::extract(post_config, contents)
+        let templates = self.shortcode_template_loader(ctx)?;
+        let site_config = self.site_config_loader(ctx)?;
+        let parse = site_config.markdown.parse_options();
+        let expanded = crate::shortcode::expand(&contents, &parse, |shortcode| {
+            let mut render_ctx = tera::Context::from_serialize(&shortcode.args)?;
+            if let Some(body) = &shortcode.body {
+                render_ctx.insert("body", body);
+            }
+            Ok(templates.render(&format!("{}.html", shortcode.name), &render_ctx)?)
+        })
+        .context(format!("expand shortcodes in post {:?}", post_config.slug))?;
+        let mut post = PostDetails::extract(post_config, &site_config.markdown, expanded.contents)
             .context(format!("extract post {:?}", post_config.slug))?;
+        post.shortcodes = expanded.outputs;
         Ok(post)
     }
 
     #[jobber::job]
     fn post_loader_by_slug(&self, ctx: &mut JobCtx<'_>, slug: &str) -> Result<PostDetails> {
-        let site_config = self.site_config_loader(ctx)?;
-        let post_config = site_config
-            .pages
-            .posts
+        let post_configs = self.post_configs(ctx)?;
+        let post_config = post_configs
             .iter()
             .find(|p| p.slug == slug)
             .context("could not find post")?;
@@ -180,32 +290,76 @@ impl Site {
     }
 
     #[jobber::job]
-    fn featured_posts(&self, ctx: &mut JobCtx<'_>) -> Result<Vec<PostDetails>> {
+    fn featured_posts(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<Vec<PostDetails>> {
         let site_config = self.site_config_loader(ctx)?;
+        let post_configs = self.post_configs(ctx)?;
         site_config
             .pages
             .featured
             .iter()
+            .filter(|&slug| {
+                post_configs
+                    .iter()
+                    .find(|p| &p.slug == slug)
+                    .is_none_or(|p| !p.is_draft(today))
+            })
             .map(|slug| self.post_loader_by_slug(ctx, slug))
             .collect()
     }
 
     #[jobber::job]
-    fn all_posts(&self, ctx: &mut JobCtx<'_>) -> Result<Vec<PostDetails>> {
+    fn all_posts(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<Vec<PostDetails>> {
         let site_config = self.site_config_loader(ctx)?;
-        site_config
-            .pages
-            .posts
+        let mut posts = self
+            .post_configs(ctx)?
             .iter()
+            .filter(|p| !p.is_draft(today))
             .map(|post_config| self.post_loader(ctx, post_config))
-            .collect()
+            .collect::<Result<Vec<_>>>()?;
+        if site_config.pages.discover_posts {
+            // Discovered posts have no configured order so list the newest first
+            posts.sort_by_key(|post| std::cmp::Reverse(post.month_year()));
+        }
+        Ok(posts)
     }
 
     #[jobber::job]
-    fn all_info(&self, ctx: &mut JobCtx<'_>) -> Result<Info> {
+    fn all_tags(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<Vec<TagPage>> {
+        let mut tags = BTreeMap::<String, TagPage>::new();
+        for post in self.all_posts(ctx, today)? {
+            for tag in post.tag_links() {
+                if tag.slug.is_empty() {
+                    anyhow::bail!(
+                        "tag {:?} of post {} has no letters or digits for its url",
+                        tag.name,
+                        post.slug
+                    );
+                }
+                let page = tags.entry(tag.slug.clone()).or_insert_with(|| TagPage {
+                    tag: tag.clone(),
+                    posts: Vec::new(),
+                });
+                // Tags differing only in case are the same tag, others would share a page
+                if page.tag.name.to_lowercase() != tag.name.to_lowercase() {
+                    anyhow::bail!(
+                        "tag {:?} of post {} has the same url /tags/{}/ as tag {:?}",
+                        tag.name,
+                        post.slug,
+                        tag.slug,
+                        page.tag.name
+                    );
+                }
+                page.posts.push(post.clone());
+            }
+        }
+        Ok(tags.into_values().collect())
+    }
+
+    #[jobber::job]
+    fn all_info(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<Info> {
         let site_config = self.site_config_loader(ctx)?;
-        let posts = self.all_posts(ctx)?;
-        let featured = self.featured_posts(ctx)?;
+        let posts = self.all_posts(ctx, today)?;
+        let featured = self.featured_posts(ctx, today)?;
         Ok(Info {
             details: site_config.details,
             posts,
@@ -245,6 +399,135 @@ impl Site {
         Ok(())
     }
 
+    /// Copy an image to the same path in the output, without its metadata if `strip_metadata`
+    #[jobber::job]
+    fn copy_image(&self, ctx: &mut JobCtx<'_>, src: &Path, strip_metadata: bool) -> Result<()> {
+        let format = match src.extension().and_then(ImageConvertFormat::from_ext) {
+            Some(format @ (ImageConvertFormat::Avif | ImageConvertFormat::Jxl))
+                if strip_metadata =>
+            {
+                log::warn!(
+                    "copying {src:?} with its metadata, which cannot be stripped from {}",
+                    format.extension()
+                );
+                return self.copyfile(ctx, src, src);
+            }
+            Some(format) if strip_metadata => format,
+            _ => return self.copyfile(ctx, src, src),
+        };
+        log::info!("Copy image {src:?} without metadata");
+        let source = self.config.root_dir.join(src);
+        let destination = self.config.output_dir.join(src);
+        ctx.depends_file(&source)?;
+        let orientation = crate::img_conversion::orientation(&source)?.to_exif();
+        let stripped = crate::img_metadata::strip(&std::fs::read(&source)?, format, orientation)
+            .context(format!("copy {source:?}"))?;
+        if let Some(dir) = destination.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(destination, stripped)?;
+        Ok(())
+    }
+
+    #[jobber::job]
+    fn render_robots(&self, ctx: &mut JobCtx<'_>) -> Result<()> {
+        let site_config = self.site_config_loader(ctx)?;
+        let source = self.config.root_dir.join("assets").join("robots.txt");
+        ctx.depends_file(&source)?;
+        let mut robots = std::fs::read_to_string(source)?;
+        if site_config.details.url.is_some() {
+            if !robots.is_empty() && !robots.ends_with('\n') {
+                robots.push('\n');
+            }
+            writeln!(
+                robots,
+                "Sitemap: {}",
+                site_config.details.absolute_url("sitemap.xml")
+            )?;
+        }
+        let destination = self.config.output_dir.join("robots.txt");
+        if let Some(dir) = destination.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(destination, robots)?;
+        Ok(())
+    }
+
+    #[jobber::job]
+    fn render_sitemap(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<()> {
+        log::info!("Render sitemap");
+        let site_config = self.site_config_loader(ctx)?;
+        let details = &site_config.details;
+        if details.url.is_none() {
+            log::warn!("details.url is not set so no sitemap is generated");
+            return Ok(());
+        }
+        let templates_dir = self.config.root_dir.join("templates");
+        let mut urls = vec![SitemapUrl {
+            loc: details.absolute_url("/"),
+            lastmod: Self::file_lastmod(ctx, &templates_dir.join("index.html"))?,
+        }];
+        for page in &site_config.pages.pages {
+            urls.push(SitemapUrl {
+                loc: details.absolute_url(&format!("{page}/")),
+                lastmod: Self::file_lastmod(ctx, &templates_dir.join(format!("{page}.html")))?,
+            });
+        }
+        for post in self.all_posts(ctx, today)? {
+            let lastmod = match post.w3c_date() {
+                Some(date) => Some(date),
+                None => Self::file_lastmod(
+                    ctx,
+                    &self
+                        .config
+                        .root_dir
+                        .join("posts")
+                        .join(format!("{}.md", post.slug)),
+                )?,
+            };
+            urls.push(SitemapUrl {
+                loc: details.absolute_url(&format!("posts/{}/", post.slug)),
+                lastmod,
+            });
+        }
+        let tags = self.all_tags(ctx, today)?;
+        urls.push(SitemapUrl {
+            loc: details.absolute_url("tags/"),
+            lastmod: tags
+                .iter()
+                .flat_map(|t| &t.posts)
+                .filter_map(PostDetails::w3c_date)
+                .max(),
+        });
+        for TagPage { tag, posts } in &tags {
+            urls.push(SitemapUrl {
+                loc: details.absolute_url(&format!(
+                    "tags/{}/",
+                    crate::sitemap::encode_path_segment(&tag.slug)
+                )),
+                lastmod: posts.iter().filter_map(PostDetails::w3c_date).max(),
+            });
+        }
+        let destination = self.config.output_dir.join("sitemap.xml");
+        if let Some(dir) = destination.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(destination, crate::sitemap::render(&urls))?;
+        Ok(())
+    }
+
+    fn file_lastmod(ctx: &mut JobCtx<'_>, path: &Path) -> Result<Option<String>> {
+        // Unlike a file, a glob can be missing and notices the file appearing
+        ctx.depends_glob(&path.display().to_string())?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let modified = std::fs::metadata(path)?.modified()?;
+        Ok(Some(
+            humantime::format_rfc3339_seconds(modified).to_string(),
+        ))
+    }
+
     #[jobber::job]
     fn template_loader(&self, ctx: &mut JobCtx<'_>) -> Result<tera::Tera> {
         let path = self
@@ -260,8 +543,17 @@ impl Site {
     }
 
     #[jobber::job]
-    fn img_tag_regex(&self, ctx: &mut JobCtx<'_>) -> Result<regex::Regex> {
-        Ok(regex::Regex::new("<img[^>]* src=\"([^\"]+)\"[^>]*>")?)
+    fn shortcode_template_loader(&self, ctx: &mut JobCtx<'_>) -> Result<tera::Tera> {
+        let path = self
+            .config
+            .root_dir
+            .join("templates")
+            .join("shortcodes")
+            .join("*.html")
+            .display()
+            .to_string();
+        ctx.depends(jobber::Leaf::Glob(path.clone()))?;
+        Ok(tera::Tera::new(&path)?)
     }
 
     fn render_template_html_common(
@@ -273,7 +565,7 @@ impl Site {
         dst: &Path,
     ) -> Result<()> {
         let html = templates.render(src, render_ctx)?;
-        let rendered = self.replace_images(ctx, html)?;
+        let rendered = self.rewrite_html(ctx, html, dst)?;
         let rendered_bytes = if self.config.minify {
             super::minify::html(&rendered)
         } else {
@@ -287,193 +579,565 @@ impl Site {
         Ok(())
     }
 
-    #[jobber::job]
-    fn replace_images(&self, ctx: &mut JobCtx<'_>, html: String) -> Result<String> {
+    /// Render `src` once for each page of `posts` with `posts` and `paginator` set in the context
+    #[expect(clippy::too_many_arguments)]
+    fn render_template_html_paginated(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        templates: &tera::Tera,
+        render_ctx: &tera::Context,
+        src: &str,
+        dst_dir: &Path,
+        base_url: &str,
+        posts: &[PostDetails],
+    ) -> Result<()> {
         let site_config = self.site_config_loader(ctx)?;
-        let img_regex = self.img_tag_regex(ctx)?;
-        let mut result = Ok(());
-        let rendered = img_regex
-            .replace_all(&html, |cap: &regex::Captures<'_>| {
-                let img = cap
-                    .get(0)
-                    .expect("regex capture")
-                    .as_str()
-                    .replace("&#x2F;", "/");
-                let path = cap
-                    .get(1)
-                    .expect("regex capture")
-                    .as_str()
-                    .replace("&#x2F;", "/");
-                path.strip_prefix('/')
-                    .map(Path::new)
-                    .and_then(|src| {
-                        self.replace_img(ctx, &img, src, &site_config.convert_images)
-                            .unwrap_or_else(|err| {
-                                if result.is_ok() {
-                                    result = Err(err);
-                                }
-                                None
-                            })
-                    })
-                    .unwrap_or_else(|| img.clone())
-            })
-            .to_string();
-        result?;
-        Ok(rendered)
+        let posts_per_page = site_config
+            .pages
+            .pagination
+            .map_or(posts.len(), |p| p.posts_per_page.get())
+            .max(1);
+        let pages: Vec<&[PostDetails]> = posts.chunks(posts_per_page).collect();
+        let total_pages = pages.len().max(1);
+        for current_page in 1..=total_pages {
+            let paginator = Paginator {
+                current_page,
+                total_pages,
+                prev: (current_page > 1).then(|| Paginator::page_url(base_url, current_page - 1)),
+                next: (current_page < total_pages)
+                    .then(|| Paginator::page_url(base_url, current_page + 1)),
+            };
+            let mut page_ctx = render_ctx.clone();
+            page_ctx.insert(
+                "posts",
+                pages.get(current_page - 1).copied().unwrap_or_default(),
+            );
+            page_ctx.insert("paginator", &paginator);
+            let dst = if current_page == 1 {
+                dst_dir.join("index.html")
+            } else {
+                dst_dir
+                    .join("page")
+                    .join(current_page.to_string())
+                    .join("index.html")
+            };
+            self.render_template_html_common(ctx, templates, &page_ctx, src, &dst)?;
+        }
+        Ok(())
     }
 
+    /// Wrap local images in `<picture>`, check alt text and add loading and opener attributes
     #[jobber::job]
-    fn img_class_regex(&self, ctx: &mut JobCtx<'_>) -> Result<regex::Regex> {
-        Ok(regex::Regex::new("class=\"([^\"]*)\"")?)
+    fn rewrite_html(&self, ctx: &mut JobCtx<'_>, html: String, dst: &Path) -> Result<String> {
+        let site_config = self.site_config_loader(ctx)?;
+        let images = &site_config.images;
+        let mut first_image = true;
+        // Found before rewriting so they convert concurrently, each only once
+        let mut local_images: Vec<(String, String, ImagePreset)> = Vec::new();
+        lol_html::rewrite_str(
+            &html,
+            lol_html::RewriteStrSettings {
+                element_content_handlers: vec![lol_html::element!("img[src]", |el| {
+                    if let Some((src, name, preset)) = local_img(el, images)?
+                        && !local_images.iter().any(|(s, n, _)| *s == src && n == name)
+                    {
+                        local_images.push((src, name.to_owned(), preset.clone()));
+                    }
+                    Ok(())
+                })],
+                ..lol_html::RewriteStrSettings::new()
+            },
+        )?;
+        let pictures = ctx.par_map(local_images, |ctx, (src, name, preset)| {
+            // The original is still linked to, such as by figures
+            self.copy_image(ctx, Path::new(&src), images.strip_metadata)?;
+            let picture = self.convert_picture(
+                ctx,
+                Path::new(&src),
+                &name,
+                &preset,
+                &site_config.convert_images,
+                images,
+            )?;
+            Ok((src, name, picture))
+        })?;
+        Ok(lol_html::rewrite_str(
+            &html,
+            lol_html::RewriteStrSettings {
+                element_content_handlers: vec![
+                    lol_html::element!("img", |el| {
+                        if !el.has_attribute("alt") {
+                            let message = format!(
+                                "image {:?} in {dst:?} has no alt text",
+                                el.get_attribute("src").unwrap_or_default()
+                            );
+                            if images.deny_missing_alt {
+                                return Err(anyhow::anyhow!(message).into());
+                            }
+                            log::warn!("{message}");
+                        }
+                        // The first image is the hero, likely in view as the page loads
+                        let attributes: &[(&str, &str)] = if first_image {
+                            first_image = false;
+                            &[("fetchpriority", "high")]
+                        } else {
+                            &[("loading", "lazy"), ("decoding", "async")]
+                        };
+                        for (name, value) in attributes {
+                            if !el.has_attribute(name) {
+                                el.set_attribute(name, value)?;
+                            }
+                        }
+                        Ok(())
+                    }),
+                    lol_html::element!("img[src]", |el| {
+                        let Some((src, name, preset)) = local_img(el, images)? else {
+                            return Ok(());
+                        };
+                        // Without a `#preset=` fragment
+                        el.set_attribute("src", &format!("/{src}"))?;
+                        let Some((_, _, Some(picture))) =
+                            pictures.iter().find(|(s, n, _)| *s == src && n == name)
+                        else {
+                            return Ok(());
+                        };
+                        let sizes = crate::rewrite::attribute(el, "sizes")
+                            .unwrap_or_else(|| images.sizes(preset).to_owned());
+                        el.set_attribute("src", &picture.fallback)?;
+                        el.set_attribute("width", &picture.width.to_string())?;
+                        el.set_attribute("height", &picture.height.to_string())?;
+                        let Placeholder { color, preview } = &picture.placeholder;
+                        if let Some(color) = color {
+                            el.set_attribute("data-dominant-color", color)?;
+                        }
+                        // Only opaque images have a preview, so it never shows through
+                        if let (Some(color), Some(preview)) = (color, preview) {
+                            let background = format!(
+                                "background: {color} url({preview}) center / cover no-repeat"
+                            );
+                            let style = match crate::rewrite::attribute(el, "style") {
+                                Some(style) => format!("{background}; {style}"),
+                                None => background,
+                            };
+                            el.set_attribute("style", &style)?;
+                        }
+                        if picture.sources.is_empty() {
+                            return Ok(());
+                        }
+                        let mut picture_html = "<picture>".to_owned();
+                        for (format, srcset) in &picture.sources {
+                            write!(
+                                picture_html,
+                                "<source srcset=\"{srcset}\" sizes=\"{sizes}\" type=\"{}\"/>",
+                                format.mime_type()
+                            )?;
+                        }
+                        el.before(&picture_html, ContentType::Html);
+                        el.after("</picture>", ContentType::Html);
+                        Ok(())
+                    }),
+                    lol_html::element!("a[target=_blank]:not([rel])", |el| {
+                        el.set_attribute("rel", "noopener noreferrer")?;
+                        Ok(())
+                    }),
+                ],
+                ..lol_html::RewriteStrSettings::new()
+            },
+        )?)
     }
 
+    /// Convert an image to each format at each width and check its fallback is within budget
     #[jobber::job]
-    fn replace_img(
+    fn convert_picture(
         &self,
         ctx: &mut JobCtx<'_>,
-        img_html: &str,
         src: &Path,
+        preset_name: &str,
+        preset: &ImagePreset,
         convert: &[ImageConvertFormat],
-    ) -> Result<Option<String>> {
-        self.copyfile(ctx, src, src)?;
+        images: &ImageConfig,
+    ) -> Result<Option<Picture>> {
         if convert.is_empty() {
             return Ok(None);
         }
-        let class_regex = self.img_class_regex(ctx)?;
 
-        let (target_cover_size, quality) = if let Some(class) = class_regex
-            .captures(img_html)
-            .and_then(|c| c.get(1))
-            .map(|c| c.as_str())
-            && class.contains("thumb")
-        {
-            ((240, 130), Quality::Thumbnail)
-        } else {
-            ((800, 800), Quality::Hero)
-        };
+        let src_fmt =
+            ImageConvertFormat::from_ext(src.extension().context("no file extension for image")?)
+                .context("unrecognized image extension")?;
+        let formats: Vec<ImageConvertFormat> = convert
+            .iter()
+            .copied()
+            // The fallback is already the source format
+            .filter(|&img_fmt| img_fmt != src_fmt && src_fmt.can_convert(img_fmt, preset.animated))
+            .collect();
+        if formats.is_empty() {
+            return Ok(None);
+        }
+
         let source = self.config.root_dir.join(src);
         ctx.depends_file(&source)?;
+        let (width, height) = preset.fit_size(
+            crate::img_conversion::dimensions(&source)
+                .context(format!("read size of {source:?}"))?,
+        );
+        let mut variant_sizes: Vec<(u32, u32)> = images
+            .widths
+            .iter()
+            .filter(|&&w| w < width)
+            .map(|&w| (w, (height * w / width).max(1)))
+            .collect();
+        variant_sizes.sort_unstable();
+        variant_sizes.dedup();
+        variant_sizes.push((width, height));
 
+        // The fallback is only needed at full size
+        let conversions = formats
+            .iter()
+            .map(|&img_fmt| (img_fmt, variant_sizes.clone()))
+            .chain([(src_fmt, vec![(width, height)])])
+            .collect();
+        let mut converted = ctx.par_map(conversions, |ctx, (img_fmt, sizes)| {
+            self.convert_image(ctx, src, preset_name, preset, img_fmt, &sizes)
+        })?;
+        let (fallback, fallback_bytes) = converted
+            .pop()
+            .and_then(|mut fallback| fallback.pop())
+            .context("missing fallback")?;
+        let original_bytes = std::fs::metadata(&source)?.len();
+        let change = |bytes: u64| {
+            let change = (i128::from(bytes) - i128::from(original_bytes)) * 100
+                / i128::from(original_bytes.max(1));
+            format!("{bytes} bytes ({change:+}%)")
+        };
+        let mut report = Vec::new();
+        // Re-encoding can make a small original larger, it can only be shown in its place if it is
+        // not cropped
+        let (fallback, fallback_bytes) =
+            if original_bytes <= fallback_bytes && preset.fit == ImageFit::Contain {
+                report.push(format!(
+                    "{} {} dropped",
+                    src_fmt.extension(),
+                    change(fallback_bytes)
+                ));
+                (format!("/{}", src.display()), original_bytes)
+            } else {
+                report.push(format!(
+                    "{} {}",
+                    src_fmt.extension(),
+                    change(fallback_bytes)
+                ));
+                (fallback, fallback_bytes)
+            };
         let mut sources = Vec::new();
-        let src_fmt =
-            ImageConvertFormat::from_ext(src.extension().context("no file extension for image")?)
-                .context("unrecognized image extension")?;
-        let (mut width, mut height) = target_cover_size;
-        for &img_fmt in convert {
-            if !src_fmt.can_convert(img_fmt) {
+        for (img_fmt, variants) in formats.into_iter().zip(converted) {
+            let Some(&(_, full_bytes)) = variants.last() else {
+                continue;
+            };
+            // Browsers that support a format would download more than the fallback
+            if full_bytes >= fallback_bytes {
+                report.push(format!(
+                    "{} {} dropped",
+                    img_fmt.extension(),
+                    change(full_bytes)
+                ));
                 continue;
             }
-            let mut new_src = src.to_path_buf();
-            new_src.set_file_name(format!(
-                "{}_{}x{}",
-                new_src.file_stem().context("file stem")?.to_string_lossy(),
-                target_cover_size.0,
-                target_cover_size.1
-            ));
-            new_src.set_extension(img_fmt.extension());
-            (width, height) = img_fmt.convert(
-                &source,
-                target_cover_size,
-                quality,
-                &self.config.output_dir.join(&new_src),
-            )?;
-            let mime_type = img_fmt.mime_type();
-            let new_path_str = new_src.display();
-            sources.push(format!(
-                "<source srcset=\"/{new_path_str}\" type=\"{mime_type}\"/>"
-            ));
+            report.push(format!("{} {}", img_fmt.extension(), change(full_bytes)));
+            let srcset: Vec<String> = variants
+                .iter()
+                .zip(&variant_sizes)
+                .enumerate()
+                // Smaller widths that are no smaller in bytes are never worth choosing
+                .filter(|&(i, ((_, bytes), _))| i == variant_sizes.len() - 1 || *bytes < full_bytes)
+                .map(|(_, ((src, _), size))| format!("{src} {}w", size.0))
+                .collect();
+            sources.push((img_fmt, srcset.join(", ")));
         }
-        if sources.is_empty() {
-            return Ok(None);
+        log::info!(
+            "Image {src:?} of {original_bytes} bytes as {}",
+            report.join(", ")
+        );
+        if let Some(max_bytes) = images.max_bytes
+            && fallback_bytes > max_bytes
+        {
+            let message =
+                format!("image {src:?} is {fallback_bytes} bytes, over the budget of {max_bytes}");
+            if images.deny_over_budget {
+                anyhow::bail!(message);
+            }
+            log::warn!("{message}");
         }
-        Ok(Some(format!(
-            "<picture>{sources}{fallback} width={width} height={height}></picture>",
-            sources = sources.join(""),
-            fallback = img_html
-                .strip_suffix(">")
-                .context("strip suffx >")?
-                .trim_end_matches('/')
-                .trim(),
-        )))
-    }
-
-    #[jobber::job]
-    fn replace_code_blocks(&self, ctx: &mut JobCtx<'_>, html: &str) -> Result<String> {
-        let regex = self.code_block_tag_regex(ctx)?;
-        Ok(regex
-            .replace_all(html, |cap: &regex::Captures<'_>| {
-                let class = cap.get(1).expect("captures").as_str();
-                let language = class.strip_prefix("language-").expect("prefix");
-                let src = cap.get(2).expect("captures").as_str();
-                crate::highlight::src_to_highlight_html(language, src)
-                    .expect("highlight code block")
-                    .unwrap_or_else(|| cap.get_match().as_str().to_string())
-            })
-            .to_string())
+        Ok(Some(Picture {
+            sources,
+            fallback,
+            placeholder: Placeholder::new(&source, (width, height))
+                .context(format!("placeholder for {source:?}"))?,
+            width,
+            height,
+        }))
     }
 
+    /// Convert to `img_fmt` at each of `sizes`, returning the url and bytes of each
     #[jobber::job]
-    fn code_block_tag_regex(&self, ctx: &mut JobCtx<'_>) -> Result<regex::Regex> {
-        Ok(regex::Regex::new(
-            "(?s)<pre><code class=\"([^\"]*)\">(.*?)</code></pre>",
-        )?)
+    fn convert_image(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        src: &Path,
+        preset_name: &str,
+        preset: &ImagePreset,
+        img_fmt: ImageConvertFormat,
+        sizes: &[(u32, u32)],
+    ) -> Result<Vec<(String, u64)>> {
+        log::info!("Convert {src:?} to {}", img_fmt.extension());
+        let source = self.config.root_dir.join(src);
+        ctx.depends_file(&source)?;
+        sizes
+            .iter()
+            .map(|&size| {
+                let mut new_src = src.to_path_buf();
+                // Two presets can give an image the same size
+                new_src.set_file_name(format!(
+                    "{}_{preset_name}_{}x{}",
+                    new_src.file_stem().context("file stem")?.to_string_lossy(),
+                    size.0,
+                    size.1
+                ));
+                new_src.set_extension(img_fmt.extension());
+                let destination = self.config.output_dir.join(&new_src);
+                img_fmt
+                    .convert(&source, size, preset, &destination)
+                    .context(format!("convert {source:?} to {}", img_fmt.extension()))?;
+                Ok((
+                    format!("/{}", new_src.display()),
+                    std::fs::metadata(destination)?.len(),
+                ))
+            })
+            .collect()
     }
 
     #[jobber::job]
-    fn render_template_html(&self, ctx: &mut JobCtx<'_>, src: &str, dst: &Path) -> Result<()> {
+    fn render_template_html(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        today: Date,
+        src: &str,
+        dst: &Path,
+    ) -> Result<()> {
         log::info!("Render {src}");
         let templates = self.template_loader(ctx)?;
-        let info = self.all_info(ctx)?;
+        let info = self.all_info(ctx, today)?;
         let mut render_ctx = tera::Context::from_serialize(info)?;
         render_ctx.insert("hot_reload", &self.include_hot_reload);
         self.render_template_html_common(ctx, &templates, &render_ctx, src, dst)
     }
 
     #[jobber::job]
-    fn render_post(&self, ctx: &mut JobCtx<'_>, post_config: &PostConfig) -> Result<()> {
+    fn render_post(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        today: Date,
+        post_config: &PostConfig,
+    ) -> Result<()> {
         log::info!("Render post {}", post_config.slug);
         let post = self.post_loader(ctx, post_config)?;
         let templates = self.template_loader(ctx)?;
-        let html_contents = post.html_contents();
+        let site_config = self.site_config_loader(ctx)?;
+        let posts = self.all_posts(ctx, today)?;
+        let related = crate::related::related_posts(&post, &posts, site_config.pages.related_posts);
+        let (prev_post, next_post) = crate::related::chronological_neighbours(&post, &posts);
+        let toc = crate::toc::build(&post.headings, &site_config.toc);
+        let html_contents = post.html_contents(&toc, &site_config.markdown)?;
+        let tag_links = post.tag_links();
+        let card = self.render_social_card(ctx, &post.slug, &post.title, &post.image)?;
+        let og = OpenGraph {
+            title: post.title.clone(),
+            description: post.description.clone(),
+            url: site_config
+                .details
+                .absolute_url(&format!("/posts/{}/", post.slug)),
+            image: site_config.details.absolute_url(&card),
+            image_width: crate::social_card::WIDTH,
+            image_height: crate::social_card::HEIGHT,
+        };
         let mut render_ctx = tera::Context::from_serialize(post)?;
         render_ctx.insert("hot_reload", &self.include_hot_reload);
         render_ctx.insert("html_contents", &html_contents);
+        render_ctx.insert("toc", &toc);
+        render_ctx.insert("tag_links", &tag_links);
+        render_ctx.insert("draft", &post_config.is_draft(today));
+        render_ctx.insert("related", &related);
+        render_ctx.insert("prev_post", &prev_post);
+        render_ctx.insert("next_post", &next_post);
+        render_ctx.insert("og", &og);
         let dst = Path::new("posts")
             .join(&post_config.slug)
             .join("index.html");
         self.render_template_html_common(ctx, &templates, &render_ctx, "post.html", &dst)
     }
 
+    /// Render the link preview card of a post to `posts/<slug>/og.png`, returning its url
     #[jobber::job]
-    fn render_all_posts(&self, ctx: &mut JobCtx<'_>) -> Result<()> {
+    fn render_social_card(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        slug: &str,
+        title: &str,
+        image: &str,
+    ) -> Result<String> {
+        log::info!("Render social card {slug}");
         let site_config = self.site_config_loader(ctx)?;
-        for post_config in &site_config.pages.posts {
+        let font = self
+            .config
+            .root_dir
+            .join("assets")
+            .join("thirdparty")
+            .join("rubik-regular.ttf");
+        ctx.depends_file(&font)?;
+        // Only local images can be drawn
+        let image = image
+            .strip_prefix('/')
+            .map(|image| self.config.root_dir.join(image));
+        if let Some(image) = &image {
+            ctx.depends_file(image)?;
+        }
+        let card = crate::social_card::render(
+            image.as_deref(),
+            title,
+            &site_config.details.name,
+            &std::fs::read(&font)?,
+        )
+        .context(format!("social card for {slug}"))?;
+        let dst = Path::new("posts").join(slug).join("og.png");
+        let destination = self.config.output_dir.join(&dst);
+        if let Some(dir) = destination.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        card.save(&destination)?;
+        Ok(format!("/{}", dst.display()))
+    }
+
+    #[jobber::job]
+    fn render_all_posts(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<()> {
+        let post_configs = self.post_configs(ctx)?;
+        for post_config in &post_configs {
+            if post_config.is_draft(today) && !self.include_drafts {
+                continue;
+            }
             if self.config.grammar_check {
                 self.spell_check_post(ctx, post_config)?;
             }
-            self.render_post(ctx, post_config)?;
+            self.render_post(ctx, today, post_config)?;
         }
         Ok(())
     }
 
     #[jobber::job]
-    fn render_all_pages(&self, ctx: &mut JobCtx<'_>) -> Result<()> {
+    fn render_all_pages(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<()> {
         let site_config = self.site_config_loader(ctx)?;
         for page in &site_config.pages.pages {
-            self.render_template_html(
-                ctx,
-                &format!("{page}.html"),
-                &Path::new(page).join("index.html"),
-            )?;
+            if site_config
+                .pages
+                .pagination
+                .as_ref()
+                .is_some_and(|p| p.pages.contains(page))
+            {
+                self.render_paginated_page(ctx, today, page)?;
+            } else {
+                self.render_template_html(
+                    ctx,
+                    today,
+                    &format!("{page}.html"),
+                    &Path::new(page).join("index.html"),
+                )?;
+            }
         }
         Ok(())
     }
 
     #[jobber::job]
-    fn render_template_js(&self, ctx: &mut JobCtx<'_>, src: &str, dst: &Path) -> Result<()> {
+    fn render_paginated_page(&self, ctx: &mut JobCtx<'_>, today: Date, page: &str) -> Result<()> {
+        log::info!("Render {page}.html");
+        let templates = self.template_loader(ctx)?;
+        let info = self.all_info(ctx, today)?;
+        let mut render_ctx = tera::Context::from_serialize(&info)?;
+        render_ctx.insert("hot_reload", &self.include_hot_reload);
+        self.render_template_html_paginated(
+            ctx,
+            &templates,
+            &render_ctx,
+            &format!("{page}.html"),
+            Path::new(page),
+            &format!("/{page}/"),
+            &info.posts,
+        )
+    }
+
+    #[jobber::job]
+    fn render_tag(&self, ctx: &mut JobCtx<'_>, today: Date, slug: &str) -> Result<()> {
+        log::info!("Render tag {slug}");
+        let templates = self.template_loader(ctx)?;
+        let info = self.all_info(ctx, today)?;
+        let tags = self.all_tags(ctx, today)?;
+        let TagPage { tag, posts } = tags
+            .iter()
+            .find(|t| t.tag.slug == slug)
+            .context("could not find tag")?;
+        let mut render_ctx = tera::Context::from_serialize(info)?;
+        render_ctx.insert("hot_reload", &self.include_hot_reload);
+        render_ctx.insert("tag", tag);
+        self.render_template_html_paginated(
+            ctx,
+            &templates,
+            &render_ctx,
+            "tag.html",
+            &Path::new("tags").join(slug),
+            &format!("/tags/{}/", crate::sitemap::encode_path_segment(slug)),
+            posts,
+        )
+    }
+
+    #[jobber::job]
+    fn render_all_tags(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<()> {
+        log::info!("Render tags");
+        let templates = self.template_loader(ctx)?;
+        let info = self.all_info(ctx, today)?;
+        let tags = self.all_tags(ctx, today)?;
+        let mut render_ctx = tera::Context::from_serialize(info)?;
+        render_ctx.insert("hot_reload", &self.include_hot_reload);
+        render_ctx.insert("tags", &tags);
+        let dst = Path::new("tags").join("index.html");
+        self.render_template_html_common(ctx, &templates, &render_ctx, "tags.html", &dst)?;
+        for TagPage { tag, .. } in &tags {
+            self.render_tag(ctx, today, &tag.slug)?;
+        }
+        Ok(())
+    }
+
+    #[jobber::job]
+    fn render_search_index(&self, ctx: &mut JobCtx<'_>, today: Date) -> Result<()> {
+        log::info!("Render search index");
+        let site_config = self.site_config_loader(ctx)?;
+        let posts = self.all_posts(ctx, today)?;
+        let index = SearchIndex::build(&posts, &site_config.markdown);
+        let destination = self.config.output_dir.join("search.json");
+        if let Some(dir) = destination.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(destination, serde_json::to_vec(&index)?)?;
+        Ok(())
+    }
+
+    #[jobber::job]
+    fn render_template_js(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        today: Date,
+        src: &str,
+        dst: &Path,
+    ) -> Result<()> {
         log::info!("Render {src}");
         let templates = self.template_loader(ctx)?;
-        let info = self.all_info(ctx)?;
+        let info = self.all_info(ctx, today)?;
         let mut render_ctx = tera::Context::from_serialize(info)?;
         render_ctx.insert("hot_reload", &self.include_hot_reload);
         let rendered = templates.render(src, &render_ctx)?;
@@ -492,10 +1156,16 @@ impl Site {
     }
 
     #[jobber::job]
-    fn render_template_css(&self, ctx: &mut JobCtx<'_>, src: &str, dst: &Path) -> Result<()> {
+    fn render_template_css(
+        &self,
+        ctx: &mut JobCtx<'_>,
+        today: Date,
+        src: &str,
+        dst: &Path,
+    ) -> Result<()> {
         log::info!("Render {src}");
         let templates = self.template_loader(ctx)?;
-        let info = self.all_info(ctx)?;
+        let info = self.all_info(ctx, today)?;
         let mut render_ctx = tera::Context::from_serialize(info)?;
         render_ctx.insert("hot_reload", &self.include_hot_reload);
         let rendered = templates.render(src, &render_ctx)?;
@@ -556,7 +1226,14 @@ impl Site {
     fn spell_check_post(&self, ctx: &mut JobCtx<'_>, post_config: &PostConfig) -> Result<()> {
         let dict = self.dictionary(ctx)?;
         let spell_ignore_list = self.spell_ignore_list(ctx)?;
+        let site_config = self.site_config_loader(ctx)?;
         let contents = self.post_markdown(ctx, post_config)?;
+        // Shortcode calls are not prose, so lint with them blanked out.
+        let contents =
+            crate::shortcode::expand(&contents, &site_config.markdown.parse_options(), |_| {
+                Ok(String::new())
+            })?
+            .contents;
         let mut md_options = MarkdownOptions::default();
         md_options.ignore_link_title = true;
         let document = Document::new_markdown(&contents, md_options, &dict);
@@ -601,3 +1278,22 @@ impl Site {
         return Err(anyhow::anyhow!("post {slug} has spelling errors"));
     }
 }
+
+/// The path of a local `<img>` without its leading `/` and its preset
+fn local_img<'a>(
+    el: &lol_html::html_content::Element<'_, '_>,
+    images: &'a ImageConfig,
+) -> Result<Option<(String, &'a str, &'a ImagePreset)>> {
+    let src = crate::rewrite::attribute(el, "src").unwrap_or_default();
+    let Some(src) = src.strip_prefix('/') else {
+        return Ok(None);
+    };
+    // Markdown images have no class, so select a preset with a url fragment
+    let (src, preset_name) = match src.split_once("#preset=") {
+        Some((src, name)) => (src, Some(name)),
+        None => (src, None),
+    };
+    let class = crate::rewrite::attribute(el, "class").unwrap_or_default();
+    let (name, preset) = images.preset(preset_name, &class)?;
+    Ok(Some((src.to_owned(), name, preset)))
+}
@@ -0,0 +1,40 @@
+use std::fmt::Write as _;
+
+use crate::rewrite::escape;
+
+#[derive(Debug, Clone)]
+pub struct SitemapUrl {
+    pub loc: String,
+    pub lastmod: Option<String>,
+}
+
+pub fn render(urls: &[SitemapUrl]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for SitemapUrl { loc, lastmod } in urls {
+        xml.push_str("  <url>\n");
+        _ = writeln!(xml, "    <loc>{}</loc>", escape(loc));
+        if let Some(lastmod) = lastmod {
+            _ = writeln!(xml, "    <lastmod>{}</lastmod>", escape(lastmod));
+        }
+        xml.push_str("  </url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+/// Percent encode a single url path segment
+pub fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+                char::from(b).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
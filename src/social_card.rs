@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use ab_glyph::{Font as _, FontRef, PxScale, ScaleFont as _};
+use anyhow::{Context as _, Result};
+
+pub const WIDTH: u32 = 1200;
+pub const HEIGHT: u32 = 630;
+
+const MARGIN: f32 = 60.0;
+const TEXT_WIDTH: f32 = 1080.0;
+const TITLE_SIZE: f32 = 72.0;
+const NAME_SIZE: f32 = 32.0;
+const MAX_TITLE_LINES: usize = 3;
+/// The dark theme background, for posts without an image
+const BACKGROUND: [u8; 3] = [0x2e, 0x2e, 0x2e];
+const TEXT_COLOR: [u8; 3] = [0xff, 0xff, 0xff];
+const NAME_COLOR: [u8; 3] = [0xcc, 0x65, 0x44];
+
+/// A link preview card of the title and site name over `image`, darkened so the text reads
+pub fn render(
+    image: Option<&Path>,
+    title: &str,
+    site_name: &str,
+    font: &[u8],
+) -> Result<image::RgbImage> {
+    let font = FontRef::try_from_slice(font).context("parse font")?;
+    let mut card = match image {
+        Some(image) => crate::img_conversion::decode(image)?
+            .resize_to_fill(WIDTH, HEIGHT, image::imageops::FilterType::Lanczos3)
+            .to_rgb8(),
+        None => image::RgbImage::from_pixel(WIDTH, HEIGHT, image::Rgb(BACKGROUND)),
+    };
+    for (_, y, pixel) in card.enumerate_pixels_mut() {
+        // From 35% darker at the top to 85% at the bottom
+        let keep = 65 * HEIGHT - 50 * y;
+        for channel in &mut pixel.0 {
+            *channel = u8::try_from(u32::from(*channel) * keep / (100 * HEIGHT)).unwrap_or(u8::MAX);
+        }
+    }
+
+    let name_baseline = HEIGHT as f32 - MARGIN;
+    draw_text(
+        &mut card,
+        &font,
+        NAME_SIZE,
+        site_name,
+        name_baseline,
+        NAME_COLOR,
+    );
+    let title_font = font.as_scaled(PxScale::from(TITLE_SIZE));
+    let line_height = title_font.height() + title_font.line_gap();
+    let lines = wrap(&font, title);
+    let mut baseline =
+        name_baseline - NAME_SIZE * 1.5 - line_height * lines.len().saturating_sub(1) as f32;
+    for line in &lines {
+        draw_text(&mut card, &font, TITLE_SIZE, line, baseline, TEXT_COLOR);
+        baseline += line_height;
+    }
+    Ok(card)
+}
+
+fn text_width(font: &FontRef<'_>, size: f32, text: &str) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// The title split into lines that fit the card, the last ending in `...` if it does not all fit
+fn wrap(font: &FontRef<'_>, title: &str) -> Vec<String> {
+    let fits = |line: &str| text_width(font, TITLE_SIZE, line) <= TEXT_WIDTH;
+    let mut lines: Vec<String> = Vec::new();
+    for word in title.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if fits(&format!("{line} {word}")) => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_owned()),
+        }
+    }
+    if lines.len() > MAX_TITLE_LINES {
+        lines.truncate(MAX_TITLE_LINES);
+        if let Some(last) = lines.last_mut() {
+            while !last.is_empty() && !fits(&format!("{last}...")) {
+                last.pop();
+            }
+            *last = format!("{}...", last.trim_end());
+        }
+    }
+    lines
+}
+
+#[expect(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn draw_text(
+    card: &mut image::RgbImage,
+    font: &FontRef<'_>,
+    size: f32,
+    text: &str,
+    baseline: f32,
+    color: [u8; 3],
+) {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut caret = MARGIN;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(size, ab_glyph::point(caret, baseline));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|x, y, coverage| {
+            let x = bounds.min.x as i64 + i64::from(x);
+            let y = bounds.min.y as i64 + i64::from(y);
+            let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y)) else {
+                return;
+            };
+            if x >= WIDTH || y >= HEIGHT {
+                return;
+            }
+            let pixel = card.get_pixel_mut(x, y);
+            for (channel, text) in pixel.0.iter_mut().zip(color) {
+                let blended = f32::from(*channel) * (1.0 - coverage) + f32::from(text) * coverage;
+                *channel = blended.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    }
+}
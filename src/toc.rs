@@ -0,0 +1,133 @@
+use std::fmt::Write as _;
+
+use serde::Serialize;
+
+use crate::{config::TocConfig, post::PostHeading};
+
+/// Markdown paragraph that is replaced with the table of contents
+const TOC_MARKER: &str = "<p>[toc]</p>";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TocEntry {
+    pub label: String,
+    pub kebab_label: String,
+    pub depth: u8,
+    pub children: Vec<Self>,
+}
+
+/// Nest headings under the closest preceding heading of a smaller depth, excluding the title
+pub fn build(headings: &[PostHeading], config: &TocConfig) -> Vec<TocEntry> {
+    let mut entries = Vec::new();
+    let title_index = headings.iter().position(|h| h.depth == 1);
+    for (_, heading) in headings
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| Some(i) != title_index)
+        .filter(|(_, h)| (config.min_depth..=config.max_depth).contains(&h.depth))
+    {
+        insert(
+            &mut entries,
+            TocEntry {
+                label: heading.label.clone(),
+                kebab_label: heading.kebab_label.clone(),
+                depth: heading.depth,
+                children: Vec::new(),
+            },
+        );
+    }
+    entries
+}
+
+fn insert(siblings: &mut Vec<TocEntry>, entry: TocEntry) {
+    if let Some(last) = siblings.last_mut()
+        && last.depth < entry.depth
+    {
+        insert(&mut last.children, entry);
+    } else {
+        siblings.push(entry);
+    }
+}
+
+/// Replace `[toc]` paragraphs in rendered post html with the table of contents
+pub fn replace_marker(html: &str, entries: &[TocEntry]) -> String {
+    if !html.contains(TOC_MARKER) {
+        return html.to_owned();
+    }
+    let mut toc = String::from("<nav class=\"toc\">");
+    write_list(&mut toc, entries);
+    toc.push_str("</nav>");
+    html.replace(TOC_MARKER, &toc)
+}
+
+fn write_list(out: &mut String, entries: &[TocEntry]) {
+    if entries.is_empty() {
+        return;
+    }
+    out.push_str("<ul>");
+    for entry in entries {
+        _ = write!(
+            out,
+            "<li><a href=\"#{}\">{}</a>",
+            crate::rewrite::escape(&entry.kebab_label),
+            crate::rewrite::escape(&entry.label)
+        );
+        write_list(out, &entry.children);
+        out.push_str("</li>");
+    }
+    out.push_str("</ul>");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TocEntry, build};
+    use crate::{config::TocConfig, post::PostHeading};
+
+    fn heading(label: &str, depth: u8) -> PostHeading {
+        PostHeading {
+            label: label.to_owned(),
+            kebab_label: label.to_lowercase(),
+            depth,
+        }
+    }
+
+    fn outline(entries: &[TocEntry]) -> Vec<(String, Vec<String>)> {
+        entries
+            .iter()
+            .map(|e| {
+                let children = e.children.iter().map(|c| c.label.clone()).collect();
+                (e.label.clone(), children)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skipped_levels() {
+        let headings = [
+            heading("Title", 1),
+            heading("A", 2),
+            heading("B", 4),
+            heading("C", 2),
+            heading("D", 3),
+            heading("E", 5),
+            heading("F", 4),
+        ];
+        let entries = build(&headings, &TocConfig::default());
+        let expected = [
+            ("A".to_owned(), vec!["B".to_owned()]),
+            ("C".to_owned(), vec!["D".to_owned()]),
+        ];
+        assert_eq!(outline(&entries), expected);
+        assert_eq!(entries[1].children[0].children[0].label, "F");
+    }
+
+    #[test]
+    fn deeper_first_heading() {
+        let headings = [heading("A", 3), heading("B", 2), heading("C", 3)];
+        let entries = build(&headings, &TocConfig::default());
+        let expected = [
+            ("A".to_owned(), vec![]),
+            ("B".to_owned(), vec!["C".to_owned()]),
+        ];
+        assert_eq!(outline(&entries), expected);
+    }
+}